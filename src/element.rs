@@ -0,0 +1,92 @@
+use std::fmt;
+use std::vec::Vec;
+
+use super::{ByteOrder, DataType};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// An element type that can be written to (and read from) a .fld payload.
+///
+/// This trait is sealed; it is implemented for `u8`, `i8`, `i16`, `u16`,
+/// `i32`, `u32`, `f32` and `f64`.
+pub trait FldElement: Copy + fmt::Display + private::Sealed {
+    /// The `data=` type describing this element stored in `order`.
+    fn data_type(order: ByteOrder) -> DataType;
+
+    /// Appends the bytes of `self` in `order` to `out`.
+    fn encode(self, order: ByteOrder, out: &mut Vec<u8>);
+
+    /// Converts `v` to `Self` with the semantics of an `as` cast.
+    fn from_f64(v: f64) -> Self;
+
+    /// Converts `v` to `Self`, or `None` if it lies outside the range of
+    /// `Self`.  Fractions are truncated toward zero for integer types.
+    fn try_from_f64(v: f64) -> Option<Self>;
+}
+
+macro_rules! fld_element {
+    ($t:ty, $le:ident, $be:ident, $ne:ident, $float:expr) => {
+        impl private::Sealed for $t {}
+
+        impl FldElement for $t {
+            fn data_type(order: ByteOrder) -> DataType {
+                match order {
+                    ByteOrder::Little => DataType::$le,
+                    ByteOrder::Big => DataType::$be,
+                    ByteOrder::Native => DataType::$ne,
+                }
+            }
+
+            fn encode(self, order: ByteOrder, out: &mut Vec<u8>) {
+                match order {
+                    ByteOrder::Little => out.extend_from_slice(&self.to_le_bytes()),
+                    ByteOrder::Big => out.extend_from_slice(&self.to_be_bytes()),
+                    ByteOrder::Native => out.extend_from_slice(&self.to_ne_bytes()),
+                }
+            }
+
+            fn from_f64(v: f64) -> $t {
+                v as $t
+            }
+
+            fn try_from_f64(v: f64) -> Option<$t> {
+                let (min, max) = (<$t>::MIN as f64, <$t>::MAX as f64);
+                let t = if $float { v } else { v.trunc() };
+                if (min <= t && t <= max) || ($float && !v.is_finite()) {
+                    Some(v as $t)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+fld_element!(u8, Byte, Byte, Byte, false);
+fld_element!(i8, Char, Char, Char, false);
+fld_element!(i16, ShortLE, XDRShort, Short, false);
+fld_element!(u16, UShortLE, UShortBE, UShort, false);
+fld_element!(i32, IntLE, XDRInt, Int, false);
+fld_element!(u32, UIntLE, UIntBE, UInt, false);
+fld_element!(f32, FloatLE, XDRFloat, Float, true);
+fld_element!(f64, DoubleLE, XDRDouble, Double, true);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_from_f64_truncates_before_range_check() {
+        assert_eq!(u8::try_from_f64(255.5), Some(255));
+        assert_eq!(u8::try_from_f64(-0.5), Some(0));
+        assert_eq!(u8::try_from_f64(256.0), None);
+        assert_eq!(i8::try_from_f64(-128.9), Some(-128));
+        assert_eq!(i8::try_from_f64(-129.0), None);
+        assert_eq!(i32::try_from_f64(f64::NAN), None);
+        assert_eq!(f32::try_from_f64(0.5), Some(0.5));
+        assert_eq!(f32::try_from_f64(1e300), None);
+        assert!(f32::try_from_f64(f64::INFINITY).unwrap().is_infinite());
+    }
+}
@@ -0,0 +1,160 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::string::String;
+
+use super::DataType;
+
+/// Everything that can go wrong reading or writing a field.
+///
+/// Header errors carry the (1-based) line they were found on, and errors
+/// raised while opening or creating a file are wrapped in `Error::File`
+/// naming it; `source()` walks down to the underlying cause.
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    /// A number in the header or an ascii payload could not be parsed.
+    Parse {
+        line: Option<usize>,
+        key: String,
+        value: String,
+        source: Option<Box<dyn error::Error + Send + Sync>>,
+    },
+    /// An unknown `data=` type.
+    DataType { line: Option<usize>, value: String },
+    /// An unknown `field=` type.
+    FieldType { line: Option<usize>, value: String },
+    /// A required header entry is absent.
+    Missing { key: String },
+    /// The header or payload is inconsistent; `reason` says how.
+    Malformed { line: Option<usize>, reason: String },
+    /// A `dimN` entry whose N is not between 1 and `ndim`.
+    DimOutOfRange { index: usize, ndim: usize },
+    /// The payload is stored as `header` but `element` was asked for, or
+    /// the other way around when writing.
+    TypeMismatch { header: DataType, element: DataType },
+    /// The data to write holds `found` values where the header needs
+    /// `expected`.
+    SizeMismatch { expected: usize, found: usize },
+    /// The payload ends after `found` of the `expected` values.
+    Truncated { expected: usize, found: usize },
+    /// Axis `axis` (counting from 1) has size zero.
+    ZeroDim { axis: usize },
+    /// The number of values in the field does not fit in a `usize`.
+    Overflow,
+    /// The payload value at `index` does not fit the requested type.
+    OutOfRange { index: usize, value: f64 },
+    /// An argument or writer option is invalid.
+    Invalid(String),
+    NotImplemented(&'static str),
+    /// `source` occurred while working on the file at `path`.
+    File { path: PathBuf, source: Box<Error> },
+}
+
+impl Error {
+    pub(crate) fn malformed<S: Into<String>>(reason: S) -> Error {
+        Error::Malformed { line: None, reason: reason.into() }
+    }
+
+    /// Parses `value` as the value of `key`.
+    pub(crate) fn parse<T>(key: &str, value: &str) -> Result<T, Error>
+            where T: std::str::FromStr,
+                  T::Err: error::Error + Send + Sync + 'static {
+        value.parse::<T>().map_err(|e| Error::Parse {
+            line: None,
+            key: key.to_string(),
+            value: value.to_string(),
+            source: Some(Box::new(e)),
+        })
+    }
+
+    /// Records the header line an error was found on, if it has none yet.
+    pub(crate) fn at_line(mut self, lineno: usize) -> Error {
+        let line = match self {
+            Error::Parse { ref mut line, .. }
+                | Error::DataType { ref mut line, .. }
+                | Error::FieldType { ref mut line, .. }
+                | Error::Malformed { ref mut line, .. } => line,
+            _ => return self,
+        };
+        line.get_or_insert(lineno);
+        self
+    }
+
+    /// Wraps the error with the path of the file it concerns.
+    pub(crate) fn in_file(self, path: &Path) -> Error {
+        Error::File { path: path.to_path_buf(), source: Box::new(self) }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IO(e)
+    }
+}
+
+fn fmt_line(f: &mut fmt::Formatter, line: &Option<usize>) -> fmt::Result {
+    match *line {
+        Some(n) => write!(f, "line {}: ", n),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IO(ref e) => write!(f, "I/O error: {}", e),
+            Error::Parse { ref line, ref key, ref value, .. } => {
+                fmt_line(f, line)?;
+                write!(f, "cannot parse {:?} as the value of {}", value, key)
+            },
+            Error::DataType { ref line, ref value } => {
+                fmt_line(f, line)?;
+                write!(f, "unknown data type {:?}", value)
+            },
+            Error::FieldType { ref line, ref value } => {
+                fmt_line(f, line)?;
+                write!(f, "unknown field type {:?}", value)
+            },
+            Error::Missing { ref key } =>
+                write!(f, "header has no {} entry", key),
+            Error::Malformed { ref line, ref reason } => {
+                fmt_line(f, line)?;
+                write!(f, "{}", reason)
+            },
+            Error::DimOutOfRange { index, ndim } =>
+                write!(f, "dim{} is out of range for ndim={}", index, ndim),
+            Error::TypeMismatch { header, element } =>
+                write!(f, "data type {} does not match element type {}",
+                       header.as_str(), element.as_str()),
+            Error::SizeMismatch { expected, found } =>
+                write!(f, "expected {} values, found {}", expected, found),
+            Error::Truncated { expected, found } =>
+                write!(f, "payload is truncated: expected {} values, found {}",
+                       expected, found),
+            Error::ZeroDim { axis } =>
+                write!(f, "dimension {} has size zero", axis),
+            Error::Overflow =>
+                write!(f, "number of values overflows usize"),
+            Error::OutOfRange { index, value } =>
+                write!(f, "value {} at index {} is out of range", value, index),
+            Error::Invalid(ref reason) => write!(f, "{}", reason),
+            Error::NotImplemented(what) =>
+                write!(f, "not implemented: {}", what),
+            Error::File { ref path, ref source } =>
+                write!(f, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IO(ref e) => Some(e),
+            Error::Parse { source: Some(ref e), .. } => Some(&**e),
+            Error::File { ref source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,1238 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::string::String;
+use std::vec::Vec;
+
+use super::{DataType, Error, FieldType, FldElement};
+
+/// Comment line placed at the top of headers created by this crate.
+pub const BANNER: &str = " AVS FLD file (written by avsfldrs github.com/greyhill/avsfldrs)";
+
+/// How the values in an external data file are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Binary,
+    /// Whitespace-separated decimal text.
+    Ascii,
+}
+
+impl FileType {
+    fn from_str(s: &str) -> Result<FileType, Error> {
+        match s {
+            "binary" => Ok(FileType::Binary),
+            "ascii" => Ok(FileType::Ascii),
+            _ => Err(Error::malformed(format!("unknown filetype {:?}", s)))
+        }
+    }
+
+    /// The token used for this type after `filetype=`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FileType::Binary => "binary",
+            FileType::Ascii => "ascii",
+        }
+    }
+}
+
+/// An external data file named by a `variable N file=...` or
+/// `coord N file=...` entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableFile {
+    /// The variable (vector component) or coordinate axis number, starting
+    /// from 1.
+    pub index: usize,
+    pub file: String,
+    pub filetype: FileType,
+    /// Bytes (binary) or lines (ascii) to skip at the start of the file.
+    pub skip: usize,
+    /// Values to skip, after `skip`, before the first value of this variable.
+    pub offset: usize,
+    /// Distance, in values, between consecutive values of this variable.
+    pub stride: usize,
+    /// Settings this crate does not recognise, such as a misspelt
+    /// `skp=512`, kept as written so that they are not silently lost.
+    /// Strict parsing rejects them instead.
+    pub unknown: Vec<String>,
+}
+
+impl VariableFile {
+    /// A contiguous binary file holding variable `index`.
+    pub fn new(index: usize, file: &str) -> VariableFile {
+        VariableFile {
+            index,
+            file: file.to_string(),
+            filetype: FileType::Binary,
+            skip: 0,
+            offset: 0,
+            stride: 1,
+            unknown: Vec::new(),
+        }
+    }
+
+    /// Parses the text to the right of `file=`: the file name followed by
+    /// optional `key=value` settings.
+    fn parse(index: usize, s: &str, strict: bool)
+            -> Result<VariableFile, Error> {
+        let words = parse_words(s);
+        let mut words = words.iter();
+        let file = words.next()
+            .ok_or_else(|| Error::malformed("missing file name"))?;
+        let mut var = VariableFile::new(index, file);
+        for word in words {
+            let mut kv = word.splitn(2, '=');
+            let mut key = kv.next().unwrap_or("").to_string();
+            let mut value = kv.next().unwrap_or("").to_string();
+            if !strict {
+                key = key.to_lowercase();
+                value = value.to_lowercase();
+            }
+            let (key, value) = (key.as_str(), value.as_str());
+            match key {
+                "filetype" => var.filetype = FileType::from_str(value)?,
+                "skip" => var.skip = Error::parse(key, value)?,
+                "offset" => var.offset = Error::parse(key, value)?,
+                "stride" => var.stride = Error::parse(key, value)?,
+                _ if strict => {
+                    let reason = match key.to_lowercase().as_str() {
+                        "filetype" | "skip" | "offset" | "stride" =>
+                            format!("file option {:?} must be lowercase", key),
+                        _ => format!("unknown file option {:?}", word),
+                    };
+                    return Err(Error::malformed(reason));
+                },
+                _ => var.unknown.push(word.to_string()),
+            }
+        }
+        if var.stride == 0 {
+            return Err(Error::malformed("stride must be positive"));
+        }
+        Ok(var)
+    }
+}
+
+/// A comment or `key=value` line of a header that this crate does not
+/// interpret.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtraLine {
+    /// A comment, without the leading `#`.
+    Comment(String),
+    /// An entry with an unknown key.  Values containing `#` or `"` are
+    /// written quoted and unquoted when parsed.
+    Entry(String, String),
+}
+
+/// Where a line of `FldHeader::extra` is written among the known entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Place {
+    /// Before the first known entry.
+    Start,
+    /// Right after the known entry with this key, spelled as `to_string`
+    /// writes it (`ndim`, `dim2`, `variable 1 file`, ...).  Lines whose
+    /// entry is not written follow the last known entry.
+    After(String),
+    /// After every known entry.
+    End,
+}
+
+/// The text header of an AVS .fld file.
+///
+/// A header can be parsed from text with `str::parse` and serialised back
+/// with `to_string`.  Known entries are written in a fixed order, with
+/// defaults such as `veclen=` spelled out; comments and unknown entries
+/// keep their place after the known entry they followed, so text written
+/// by `to_string` parses back to a header that writes the same text.  The
+/// `\x0c\x0c` separator that precedes an inline payload is not part of the
+/// header.
+/// `from_reader` and `from_bytes` also report where the payload begins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FldHeader {
+    pub ndim: usize,
+    pub dims: Vec<usize>,
+    pub nspace: usize,
+    pub veclen: usize,
+    pub data: DataType,
+    pub field: FieldType,
+    /// Axis labels from `label=`; labels containing spaces, `"` or `#` are
+    /// quoted.
+    pub labels: Vec<String>,
+    /// Axis units from `unit=`, quoted like `labels`.
+    pub units: Vec<String>,
+    /// Spatial extents of the field, one value per axis.
+    pub min_ext: Vec<f64>,
+    pub max_ext: Vec<f64>,
+    /// Range of the data, one value per vector component.
+    pub min_val: Vec<f64>,
+    pub max_val: Vec<f64>,
+    /// Comment lines and entries this crate does not interpret, in header
+    /// order.
+    pub extra: Vec<(Place, ExtraLine)>,
+    pub variables: Vec<VariableFile>,
+    /// `coord N file=...` entries of rectilinear and irregular fields.
+    pub coords: Vec<VariableFile>,
+}
+
+impl FldHeader {
+    /// A uniform, scalar header for an array of the given dimensions.
+    pub fn new(dims: &[usize], data: DataType) -> FldHeader {
+        FldHeader {
+            ndim: dims.len(),
+            dims: dims.to_vec(),
+            nspace: dims.len(),
+            veclen: 1,
+            data,
+            field: FieldType::Uniform,
+            labels: Vec::new(),
+            units: Vec::new(),
+            min_ext: Vec::new(),
+            max_ext: Vec::new(),
+            min_val: Vec::new(),
+            max_val: Vec::new(),
+            extra: vec![
+                (Place::Start, ExtraLine::Comment(BANNER.to_string()))],
+            variables: Vec::new(),
+            coords: Vec::new(),
+        }
+    }
+
+    /// The type of values in binary coordinate files: `float`, in the byte
+    /// order of `data`.
+    pub fn coord_type(&self) -> DataType {
+        f32::data_type(self.data.byte_order())
+    }
+
+    /// Appends a comment line ahead of the known entries; a comment
+    /// containing newlines becomes several lines.
+    pub fn add_comment(&mut self, comment: &str) {
+        for line in comment.lines() {
+            self.extra.push(
+                (Place::Start, ExtraLine::Comment(format!(" {}", line))));
+        }
+    }
+
+    /// The comment lines of `extra`, without the leading `#`.
+    pub fn comments(&self) -> Vec<&str> {
+        self.extra.iter()
+            .filter_map(|(_, line)| match *line {
+                ExtraLine::Comment(ref comment) => Some(comment.as_str()),
+                ExtraLine::Entry(..) => None,
+            })
+            .collect()
+    }
+
+    /// Appends an extra `key=value` entry after the known entries.  `key`
+    /// must not be one this crate interprets, in any case, and neither may
+    /// contain a line break; `key` may not contain `=`, `#` or `"` either.
+    /// Values containing `#` or `"` are quoted when written.
+    pub fn add_entry(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let key = key.trim();
+        let breaks = |s: &str| s.contains(['\n', '\r']);
+        if key.is_empty() || key.contains(['=', '#', '"'])
+                || breaks(key) || breaks(value)
+                || is_reserved_key(&fold(key)) {
+            return Err(Error::Invalid(
+                format!("cannot add header entry {:?}", key)));
+        }
+        self.extra.push((Place::End, ExtraLine::Entry(
+            key.to_string(), value.trim().to_string())));
+        Ok(())
+    }
+
+    /// The number of grid points, i.e. the product of `dims`.
+    pub fn num_points(&self) -> usize {
+        self.dims.iter().product::<usize>()
+    }
+
+    /// The number of scalar values in the payload, `num_points() * veclen`.
+    pub fn num_values(&self) -> usize {
+        self.num_points() * self.veclen
+    }
+
+    /// The distance between neighbouring grid points along each axis of a
+    /// uniform field, from `min_ext` and `max_ext`.  `None` if the field is
+    /// not uniform or its extents are missing.  Axes with a single point
+    /// have zero spacing.
+    pub fn spacing(&self) -> Option<Vec<f64>> {
+        if self.field != FieldType::Uniform
+                || self.min_ext.len() != self.ndim
+                || self.max_ext.len() != self.ndim {
+            return None;
+        }
+        Some(self.dims.iter().enumerate()
+             .map(|(axis, &size)| if size > 1 {
+                 (self.max_ext[axis] - self.min_ext[axis]) / (size - 1) as f64
+             } else {
+                 0.0
+             })
+             .collect())
+    }
+
+    /// The world coordinates of the grid point with zero-based `index` in a
+    /// uniform field; `None` where `spacing` is, or if `index` has the
+    /// wrong length or lies outside the grid.
+    pub fn world_coord(&self, index: &[usize]) -> Option<Vec<f64>> {
+        let spacing = self.spacing()?;
+        if index.len() != self.ndim
+                || index.iter().zip(self.dims.iter()).any(|(&i, &n)| i >= n) {
+            return None;
+        }
+        Some(index.iter().enumerate()
+             .map(|(axis, &i)| self.min_ext[axis] + i as f64 * spacing[axis])
+             .collect())
+    }
+
+    /// Like `num_values`, but fails if there are no axes, an axis has size
+    /// zero or the count overflows.
+    pub fn checked_num_values(&self) -> Result<usize, Error> {
+        if self.dims.is_empty() {
+            return Err(Error::malformed("field has no dimensions"));
+        }
+        let mut count = self.veclen;
+        for (axis, &size) in self.dims.iter().enumerate() {
+            if size == 0 {
+                return Err(Error::ZeroDim { axis: axis + 1 });
+            }
+            count = count.checked_mul(size).ok_or(Error::Overflow)?;
+        }
+        Ok(count)
+    }
+
+    /// Checks that the entries agree with each other: `dims` holds `ndim`
+    /// sizes, `veclen` is positive, and the `variable N` and `coord N`
+    /// entries are numbered within range and without repeats, with a
+    /// `coord N` entry for every coordinate array of a rectilinear or
+    /// irregular field.  Headers are checked when parsed and again before
+    /// they are written.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.dims.len() != self.ndim {
+            return Err(Error::malformed(format!(
+                "{} dimensions for ndim={}", self.dims.len(), self.ndim)));
+        }
+        if self.veclen == 0 {
+            return Err(Error::malformed("veclen must be positive"));
+        }
+        // either one file holding every component, or one file per component
+        check_indices("variable", &self.variables, self.veclen)?;
+        match self.variables.len() {
+            0 => {},
+            1 if self.variables[0].index != 1 =>
+                return Err(Error::malformed(format!(
+                    "a single variable file must be variable 1, not {}",
+                    self.variables[0].index))),
+            1 => {},
+            n if n != self.veclen =>
+                return Err(Error::malformed(format!(
+                    "{} variable files for veclen={}", n, self.veclen))),
+            _ => {},
+        }
+        // rectilinear and irregular fields need every coordinate array
+        let (ncoords, complete) = match self.field {
+            FieldType::Uniform => (self.ndim, false),
+            FieldType::Rectilinear => (self.ndim, true),
+            FieldType::Irregular => (self.nspace, true),
+        };
+        check_indices("coord", &self.coords, ncoords)?;
+        if complete && self.coords.len() < ncoords {
+            let absent = (1 ..= ncoords)
+                .find(|&idx| self.coords.iter().all(|c| c.index != idx))
+                .unwrap_or(ncoords);
+            return Err(missing(&format!("coord {} file", absent)));
+        }
+        if self.field == FieldType::Irregular && self.nspace == 0 {
+            return Err(Error::malformed("nspace must be positive"));
+        }
+        Ok(())
+    }
+
+    /// Checks that `coords[N-1]` holds the values of every `coord N` entry,
+    /// as many as are read back: `num_points()` for an irregular field,
+    /// otherwise `dims[N-1]`.  Expects a header that passes `check`.
+    pub(crate) fn check_coords(&self, coords: &[Vec<f32>])
+            -> Result<(), Error> {
+        for entry in &self.coords {
+            let values = coords.get(entry.index - 1).ok_or_else(|| {
+                Error::Invalid(format!(
+                    "no coordinates given for coord {}", entry.index))
+            })?;
+            let expected = match self.field {
+                FieldType::Irregular => self.num_points(),
+                _ => self.dims[entry.index - 1],
+            };
+            if values.len() != expected {
+                return Err(Error::Invalid(format!(
+                    "{} coordinates given for coord {}, expected {}",
+                    values.len(), entry.index, expected)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses header text as `str::parse` does, but rejects inline comments,
+    /// keys and keywords that are not lowercase, and lines without a `=`;
+    /// useful for checking that a header will be read by other programs.
+    pub fn parse_strict(s: &str) -> Result<FldHeader, Error> {
+        FldHeader::parse_text(s, true)
+    }
+
+    fn parse_text(s: &str, strict: bool) -> Result<FldHeader, Error> {
+        let text = match s.find('\x0c') {
+            Some(end) => &s[.. end],
+            None => s,
+        };
+        let mut parser = Parser::new(strict);
+        for line in text.lines() {
+            parser.line(line)?;
+        }
+        parser.finish()
+    }
+
+    /// Reads a header from `reader`, stopping just after the `\x0c\x0c`
+    /// separator so that the reader is left at the start of the payload.
+    /// Returns the header and the number of bytes read, which is the offset
+    /// of the payload from where the header began.  No other files are
+    /// opened, even if the header names some.
+    ///
+    /// A header whose data lives entirely in `variable` files may instead
+    /// end at the end of the input, as separate header files often do.
+    /// Parsing is as lenient as `str::parse`.
+    pub fn from_reader<R: Read>(reader: &mut R)
+            -> Result<(FldHeader, usize), Error> {
+        let mut parser = Parser::new(false);
+        let mut line = Vec::<u8>::new();
+        let mut last_char: u8 = 0;
+        let mut offset = 0;
+        loop {
+            let mut new_char_buf: [u8;1] = [ 0u8 ];
+            match reader.read(&mut new_char_buf) {
+                Ok(0) => {
+                    let line = String::from_utf8_lossy(&line);
+                    return Ok((parser.finish_at_eof(&line)?, offset));
+                },
+                Ok(_) => offset += 1,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::from(e)),
+            }
+
+            // break on two chr 12s, after any unterminated last line
+            let new_char = new_char_buf[0];
+            if (new_char, last_char) == (12u8, 12u8) {
+                line.pop();
+                if !line.is_empty() {
+                    parser.line(&String::from_utf8_lossy(&line))?;
+                }
+                break;
+            }
+            last_char = new_char;
+
+            line.push(new_char);
+
+            // new line; process the line and discard
+            if new_char == 10 {
+                parser.line(&String::from_utf8_lossy(&line))?;
+                line.clear();
+            }
+        }
+        Ok((parser.finish()?, offset))
+    }
+
+    /// Parses the header at the start of `bytes`, which may hold a complete
+    /// .fld file, returning it with the offset of the payload in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(FldHeader, usize), Error> {
+        FldHeader::from_reader(&mut &bytes[..])
+    }
+}
+
+impl FromStr for FldHeader {
+    type Err = Error;
+
+    /// Parses header text.  Parsing stops at the first form feed, so the
+    /// leading part of a complete .fld file may be passed in as well.
+    ///
+    /// Keys and keywords are matched without regard to case or repeated
+    /// whitespace, `#` starts a comment anywhere outside double quotes (it
+    /// is kept in `extra`), and lines without a `=` are skipped.
+    /// `FldHeader::parse_strict` accepts only the canonical form.
+    fn from_str(s: &str) -> Result<FldHeader, Error> {
+        FldHeader::parse_text(s, false)
+    }
+}
+
+impl fmt::Display for VariableFile {
+    /// Formats the `file=...` part of the entry.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "file={} filetype={} skip={} offset={} stride={}",
+               quote_word(&self.file), self.filetype.as_str(),
+               self.skip, self.offset, self.stride)?;
+        for word in &self.unknown {
+            write!(f, " {}", quote_word(word))?;
+        }
+        Ok(())
+    }
+}
+
+fn join<T: fmt::Display>(values: &[T]) -> String {
+    values.iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl fmt::Display for ExtraLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExtraLine::Comment(ref comment) => write!(f, "#{}", comment),
+            ExtraLine::Entry(ref key, ref value) =>
+                write!(f, "{}={}", key, quote_value(value)),
+        }
+    }
+}
+
+impl FldHeader {
+    /// Writes the lines of `extra` whose place satisfies `at`.
+    fn write_extra<F: Fn(&Place) -> bool>(&self, f: &mut fmt::Formatter, at: F)
+            -> fmt::Result {
+        for (_, line) in self.extra.iter().filter(|&(place, _)| at(place)) {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FldHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // (key, line) for each known entry, in the order written
+        let mut known = vec![
+            ("ndim".to_string(), format!("ndim={}", self.ndim)),
+            ("veclen".to_string(), format!("veclen={}", self.veclen)),
+            ("nspace".to_string(), format!("nspace={}", self.nspace)),
+            ("field".to_string(), format!("field={}", self.field.as_str())),
+            ("data".to_string(), format!("data={}", self.data.as_str())),
+        ];
+        for (id, size) in self.dims.iter().enumerate() {
+            known.push((format!("dim{}", id+1),
+                        format!("dim{}={}", id+1, size)));
+        }
+        let lists = [("min_ext", &self.min_ext), ("max_ext", &self.max_ext),
+                     ("min_val", &self.min_val), ("max_val", &self.max_val)];
+        for &(key, values) in &lists {
+            if !values.is_empty() {
+                known.push((key.to_string(),
+                            format!("{}={}", key, join(values))));
+            }
+        }
+        if !self.labels.is_empty() {
+            known.push(("label".to_string(),
+                        format!("label={}", quote_words(&self.labels))));
+        }
+        if !self.units.is_empty() {
+            known.push(("unit".to_string(),
+                        format!("unit={}", quote_words(&self.units))));
+        }
+        for (kind, files) in &[("variable", &self.variables),
+                               ("coord", &self.coords)] {
+            for file in files.iter() {
+                known.push((format!("{} {} file", kind, file.index),
+                            format!("{} {} {}", kind, file.index, file)));
+            }
+        }
+
+        self.write_extra(f, |place| *place == Place::Start)?;
+        for (key, line) in &known {
+            writeln!(f, "{}", line)?;
+            self.write_extra(f, |place| match *place {
+                Place::After(ref after) => after == key,
+                _ => false,
+            })?;
+        }
+        self.write_extra(f, |place| match *place {
+            Place::After(ref after) =>
+                known.iter().all(|(key, _)| key != after),
+            _ => false,
+        })?;
+        self.write_extra(f, |place| *place == Place::End)
+    }
+}
+
+/// Joins `words` with spaces, quoting them as `quote_word` does.
+fn quote_words(words: &[String]) -> String {
+    words.iter()
+        .map(|w| quote_word(w))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Double-quotes `word` if it is empty or contains whitespace, `"` or `#`.
+fn quote_word(word: &str) -> String {
+    if !word.is_empty()
+            && !word.contains(|c: char| c.is_whitespace() || c == '"' || c == '#') {
+        return word.to_string();
+    }
+    quote(word)
+}
+
+/// Double-quotes an extra entry's value if it contains `"` or `#`, or has
+/// whitespace at either end; the parser unquotes it again.
+fn quote_value(value: &str) -> String {
+    if !value.contains(['"', '#']) && value.trim() == value {
+        return value.to_string();
+    }
+    quote(value)
+}
+
+/// The text of `value` if it is a single string quoted as `quote` does.
+fn unquote(value: &str) -> Option<String> {
+    let mut chars = value.strip_prefix('"')?.chars();
+    let mut text = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.push(chars.next()?),
+            '"' => return if chars.next().is_none() { Some(text) } else { None },
+            _ => text.push(c),
+        }
+    }
+    None
+}
+
+/// Double-quotes `s`, escaping `"` and `\` inside the quotes with a
+/// backslash.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits `s` on whitespace, keeping double-quoted words together; within
+/// quotes a backslash escapes the next character.
+fn parse_words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let quoted = match chars.peek() {
+            None => break,
+            Some(&'"') => {
+                chars.next();
+                true
+            },
+            Some(_) => false,
+        };
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if (quoted && c == '"') || (!quoted && c.is_whitespace()) {
+                break;
+            }
+            chars.next();
+            match chars.peek() {
+                Some(&escaped) if quoted && c == '\\' => {
+                    word.push(escaped);
+                    chars.next();
+                },
+                _ => word.push(c),
+            }
+        }
+        if quoted {
+            chars.next();
+        }
+        words.push(word);
+    }
+    words
+}
+
+fn parse_floats(key: &str, s: &str) -> Result<Vec<f64>, Error> {
+    s.split_whitespace()
+        .map(|w| Error::parse(key, w))
+        .collect()
+}
+
+/// True for keys with a meaning to this crate.
+fn is_reserved_key(key: &str) -> bool {
+    match key {
+        "ndim" | "nspace" | "veclen" | "data" | "field" | "label" | "unit"
+            | "min_ext" | "max_ext" | "min_val" | "max_val" => true,
+        _ => is_dim_key(key) || file_key(key).is_some(),
+    }
+}
+
+/// Splits `line` at the first `#` that is not inside double quotes, where
+/// quotes are as in `parse_words`, into the text before it and the comment
+/// after it.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut chars = line.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => {
+                chars.next();
+            },
+            '#' if !quoted => return (&line[.. pos], Some(&line[pos + 1 ..])),
+            _ => {},
+        }
+    }
+    (line, None)
+}
+
+/// Lowercases `key` and collapses runs of whitespace, so that keys match
+/// however they are spelled.
+fn fold(key: &str) -> String {
+    key.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// True for `dimN` keys, where N is a decimal axis number.
+fn is_dim_key(key: &str) -> bool {
+    key.len() > 3 && key.starts_with("dim")
+        && key[3..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The kind (`variable` or `coord`) and N of a `variable N file` or
+/// `coord N file` key.
+fn file_key(key: &str) -> Option<(&str, &str)> {
+    let words: Vec<&str> = key.split_whitespace().collect();
+    match words[..] {
+        [kind, idx, "file"] if kind == "variable" || kind == "coord" =>
+            Some((kind, idx)),
+        _ => None,
+    }
+}
+
+/// `key`, a known key, as `Display` writes it: `dim1` for `dim01`, say.
+fn canonical_key(key: &str) -> String {
+    let number = |s: &str| s.parse::<usize>()
+        .map(|n| n.to_string())
+        .unwrap_or_else(|_| s.to_string());
+    if is_dim_key(key) {
+        return format!("dim{}", number(&key[3..]));
+    }
+    match file_key(key) {
+        Some((kind, idx)) => format!("{} {} file", kind, number(idx)),
+        None => key.to_string(),
+    }
+}
+
+/// Checks that `kind N file` indices are unique and between 1 and `max`.
+fn check_indices(kind: &str, files: &[VariableFile], max: usize)
+        -> Result<(), Error> {
+    let mut indices: Vec<usize> = files.iter().map(|f| f.index).collect();
+    indices.sort_unstable();
+    for (pos, &index) in indices.iter().enumerate() {
+        if index == 0 || index > max || (pos > 0 && indices[pos - 1] == index) {
+            return Err(Error::malformed(format!(
+                "{} {} is repeated or out of range", kind, index)));
+        }
+    }
+    Ok(())
+}
+
+fn missing(key: &str) -> Error {
+    Error::Missing { key: key.to_string() }
+}
+
+/// Accumulates header lines; required entries are checked by `finish`.
+struct Parser {
+    /// Whether to reject the variations tolerated by `parse_line`.
+    strict: bool,
+    /// The number of lines seen so far.
+    lineno: usize,
+    ndim: Option<usize>,
+    /// `(N, size)` for each `dimN=size` line, in header order.
+    sizes: Vec<(usize, usize)>,
+    nspace: Option<usize>,
+    veclen: Option<usize>,
+    data_type: Option<DataType>,
+    field_type: Option<FieldType>,
+    labels: Vec<String>,
+    units: Vec<String>,
+    min_ext: Vec<f64>,
+    max_ext: Vec<f64>,
+    min_val: Vec<f64>,
+    max_val: Vec<f64>,
+    extra: Vec<(Place, ExtraLine)>,
+    /// The key of the last known entry, which anchors the lines after it.
+    last: Option<String>,
+    variables: Vec<VariableFile>,
+    coords: Vec<VariableFile>,
+}
+
+impl Parser {
+    fn new(strict: bool) -> Parser {
+        Parser {
+            strict,
+            lineno: 0,
+            ndim: None,
+            sizes: Vec::new(),
+            nspace: None,
+            veclen: None,
+            data_type: None,
+            field_type: None,
+            labels: Vec::new(),
+            units: Vec::new(),
+            min_ext: Vec::new(),
+            max_ext: Vec::new(),
+            min_val: Vec::new(),
+            max_val: Vec::new(),
+            extra: Vec::new(),
+            last: None,
+            variables: Vec::new(),
+            coords: Vec::new(),
+        }
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), Error> {
+        self.lineno += 1;
+        let lineno = self.lineno;
+        self.parse_line(line).map_err(|e| e.at_line(lineno))
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+        let comment = if self.strict { line.strip_prefix('#') }
+                      else { line.trim_start().strip_prefix('#') };
+        if let Some(comment) = comment {
+            self.push(ExtraLine::Comment(comment.to_string()));
+            return Ok(());
+        }
+        if self.strict {
+            return self.parse_entry(line);
+        }
+
+        // an inline comment is kept as a line of its own after the entry
+        let (line, comment) = split_comment(line);
+        self.parse_entry(line)?;
+        if let Some(comment) = comment {
+            self.push(ExtraLine::Comment(comment.to_string()));
+        }
+        Ok(())
+    }
+
+    fn parse_entry(&mut self, line: &str) -> Result<(), Error> {
+        let (name, value) = match line.find('=') {
+            Some(eq) => (line[.. eq].trim(), line[eq + 1 ..].trim()),
+            None if self.strict && !line.trim().is_empty() =>
+                return Err(Error::malformed(
+                    format!("expected key=value, found {:?}", line))),
+            None => return Ok(()),
+        };
+        let key = self.fold(name);
+        if self.strict && !is_reserved_key(name)
+                && is_reserved_key(&name.to_lowercase()) {
+            return Err(Error::malformed(
+                format!("key {:?} must be lowercase", name)));
+        }
+        match key.as_str() {
+            "ndim" => self.ndim = Some(Error::parse("ndim", value)?),
+            key if is_dim_key(key) => {
+                let idx = Error::parse(key, &key[3..])?;
+                self.sizes.push((idx, Error::parse(key, value)?));
+            },
+            "nspace" => self.nspace = Some(Error::parse("nspace", value)?),
+            "veclen" => self.veclen = Some(Error::parse("veclen", value)?),
+            "data" =>
+                self.data_type = Some(DataType::from_str(&self.fold(value))?),
+            "field" =>
+                self.field_type = Some(FieldType::from_str(&self.fold(value))?),
+            "label" => self.labels = parse_words(value),
+            "unit" => self.units = parse_words(value),
+            "min_ext" => self.min_ext = parse_floats("min_ext", value)?,
+            "max_ext" => self.max_ext = parse_floats("max_ext", value)?,
+            "min_val" => self.min_val = parse_floats("min_val", value)?,
+            "max_val" => self.max_val = parse_floats("max_val", value)?,
+            key if file_key(key).is_some() => {
+                let (kind, idx) = file_key(key).unwrap();
+                let idx = Error::parse(key, idx)?;
+                let entry = VariableFile::parse(idx, value, self.strict)?;
+                if kind == "coord" {
+                    self.coords.push(entry);
+                } else {
+                    self.variables.push(entry);
+                }
+            },
+            "" => {},
+            _ => {
+                let value = unquote(value).unwrap_or_else(|| value.to_string());
+                self.push(ExtraLine::Entry(name.to_string(), value));
+            },
+        }
+        if is_reserved_key(&key) {
+            self.last = Some(canonical_key(&key));
+        }
+        Ok(())
+    }
+
+    /// Keeps `line` after the last known entry so far.
+    fn push(&mut self, line: ExtraLine) {
+        let place = match self.last {
+            Some(ref key) => Place::After(key.clone()),
+            None => Place::Start,
+        };
+        self.extra.push((place, line));
+    }
+
+    /// Normalises a key or keyword for matching: unchanged in strict mode,
+    /// otherwise lowercased with runs of whitespace collapsed.
+    fn fold(&self, s: &str) -> String {
+        if self.strict {
+            s.to_string()
+        } else {
+            fold(s)
+        }
+    }
+
+    /// Finishes a header that ran into the end of its input, after the
+    /// unterminated last `line`.
+    fn finish_at_eof(mut self, line: &str) -> Result<FldHeader, Error> {
+        self.line(line)?;
+        if self.variables.is_empty() {
+            return Err(Error::malformed(
+                "unexpected end of file before the \\f\\f header separator"));
+        }
+        self.finish()
+    }
+
+    fn finish(self) -> Result<FldHeader, Error> {
+        let ndim = self.ndim.ok_or_else(|| missing("ndim"))?;
+        if let Some(&(index, _)) = self.sizes.iter()
+                .find(|&&(idx, _)| idx == 0 || idx > ndim) {
+            return Err(Error::DimOutOfRange { index, ndim });
+        }
+        // check before allocating, as ndim comes straight from the file
+        if self.sizes.len() < ndim {
+            let first_missing = (1 ..= ndim)
+                .find(|&idx| self.sizes.iter().all(|&(seen, _)| seen != idx))
+                .unwrap_or(ndim);
+            return Err(missing(&format!("dim{}", first_missing)));
+        }
+        let mut dims: Vec<Option<usize>> = vec![None; ndim];
+        for (idx, size) in self.sizes {
+            dims[idx - 1] = Some(size);
+        }
+        let dims = dims.into_iter()
+            .enumerate()
+            .map(|(idx, s)| s.ok_or_else(|| missing(&format!("dim{}", idx + 1))))
+            .collect::<Result<Vec<usize>, Error>>()?;
+        let field = self.field_type.ok_or_else(|| missing("field"))?;
+        // lines after the last known entry stay at the end when rewritten
+        let mut extra = self.extra;
+        if let Some(last) = self.last {
+            for (place, _) in &mut extra {
+                if *place == Place::After(last.clone()) {
+                    *place = Place::End;
+                }
+            }
+        }
+        let header = FldHeader {
+            ndim,
+            dims,
+            nspace: self.nspace.unwrap_or(ndim),
+            veclen: self.veclen.unwrap_or(1),
+            data: self.data_type.ok_or_else(|| missing("data"))?,
+            field,
+            labels: self.labels,
+            units: self.units,
+            min_ext: self.min_ext,
+            max_ext: self.max_ext,
+            min_val: self.min_val,
+            max_val: self.max_val,
+            extra,
+            variables: self.variables,
+            coords: self.coords,
+        };
+        header.check()?;
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCALAR: &str = "ndim=1\ndim1=4\ndata=byte\nfield=uniform\n";
+
+    fn parse_err(text: &str) -> Error {
+        match text.parse::<FldHeader>() {
+            Ok(header) => panic!("parsed {:?}", header),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut header = FldHeader::new(&[2, 3], DataType::XDRFloat);
+        header.veclen = 2;
+        header.field = FieldType::Rectilinear;
+        header.min_ext = vec![0.0, -1.5];
+        header.max_ext = vec![1.0, 2.25];
+        header.min_val = vec![-3.0, 0.0];
+        header.max_val = vec![3.0, 1e-3];
+        header.labels = vec!["x".to_string(), "depth (z)".to_string()];
+        header.units = vec!["mm".to_string(), "mm".to_string()];
+        header.add_comment("provenance\nsecond line");
+        header.add_entry("recon", "fbp filter=ramp").unwrap();
+        header.variables.push(VariableFile {
+            filetype: FileType::Ascii,
+            skip: 1,
+            offset: 1,
+            stride: 2,
+            ..VariableFile::new(1, "data.txt")
+        });
+        header.coords.push(VariableFile::new(1, "x.crd"));
+        header.coords.push(VariableFile::new(2, "y.crd"));
+        let text = header.to_string();
+        let parsed: FldHeader = text.parse().unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.to_string(), text);
+        assert_eq!(FldHeader::parse_strict(&text).unwrap(), header);
+    }
+
+    #[test]
+    fn huge_veclen_is_checked_without_allocating() {
+        let text = format!("{}veclen=100000000000000\n\
+                            variable 1 file=a\nvariable 1 file=b\n", SCALAR);
+        match parse_err(&text) {
+            Error::Malformed { reason, .. } =>
+                assert_eq!(reason, "variable 1 is repeated or out of range"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn single_variable_file_must_be_variable_1() {
+        let text = format!("{}veclen=3\nvariable 2 file=a\n", SCALAR);
+        match parse_err(&text) {
+            Error::Malformed { .. } => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        let text = format!("{}veclen=3\nvariable 1 file=a\n", SCALAR);
+        assert_eq!(text.parse::<FldHeader>().unwrap().variables.len(), 1);
+    }
+
+    #[test]
+    fn rectilinear_needs_every_coord_file() {
+        let text = "ndim=2\ndim1=2\ndim2=3\ndata=float\nfield=rectilinear\n\
+                    coord 2 file=y\n";
+        match parse_err(text) {
+            Error::Missing { key } => assert_eq!(key, "coord 1 file"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let text = "ndim=1\ndim1=2\nnspace=3\ndata=float\nfield=irregular\n\
+                    coord 1 file=x\ncoord 2 file=y\n";
+        match parse_err(text) {
+            Error::Missing { key } => assert_eq!(key, "coord 3 file"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn labels_round_trip() {
+        let mut header = FldHeader::new(&[2, 2, 2, 2, 2, 2], DataType::Byte);
+        header.labels = vec!["x".to_string(), "a\"b c".to_string(),
+                             "x#1".to_string(), "".to_string(),
+                             "back\\slash \\".to_string(), "\"".to_string()];
+        header.units = vec!["mm".to_string(); 6];
+        let parsed: FldHeader = header.to_string().parse().unwrap();
+        assert_eq!(parsed.labels, header.labels);
+        assert_eq!(parsed.units, header.units);
+    }
+
+    #[test]
+    fn extra_values_round_trip() {
+        let mut header = FldHeader::new(&[4], DataType::Byte);
+        header.add_entry("git", "abc#123").unwrap();
+        header.add_entry("quote", "say \"hi\"").unwrap();
+        header.add_entry("expr", "a=b").unwrap();
+        header.add_entry("empty", "").unwrap();
+        header.extra.push((Place::End, ExtraLine::Entry(
+            "padded".to_string(), " x ".to_string())));
+        let text = header.to_string();
+        assert_eq!(text.parse::<FldHeader>().unwrap(), header);
+        assert_eq!(FldHeader::parse_strict(&text).unwrap(), header);
+    }
+
+    #[test]
+    fn add_entry_rejects_reserved_keys_in_any_case() {
+        let mut header = FldHeader::new(&[4], DataType::Byte);
+        for key in &["Data", "NDIM", "Variable  1 File", "dim1", "a#b", "a\"b"] {
+            assert!(header.add_entry(key, "x").is_err(), "{}", key);
+        }
+        assert_eq!(header.extra.len(), 1);
+    }
+
+    #[test]
+    fn lenient_parse() {
+        let text = "# AVS\r\n  NDIM = 1   # one axis\r\nDim1=4 # provenance\r\n\
+                    DATA=FLOAT_LE\r\nField=Uniform\r\nlabel=\"a # b\"\r\n\
+                    Author=x=y\r\nvariable   1  FILE=d.raw FILETYPE=Binary skip=2\r\n\
+                    garbage line\r\n";
+        let header: FldHeader = text.parse().unwrap();
+        assert_eq!(header.ndim, 1);
+        assert_eq!(header.dims, vec![4]);
+        assert_eq!(header.data, DataType::FloatLE);
+        assert_eq!(header.labels, vec!["a # b"]);
+        assert_eq!(header.comments(), vec![" AVS", " one axis", " provenance"]);
+        assert_eq!(header.extra[3],
+                   (Place::After("label".to_string()),
+                    ExtraLine::Entry("Author".to_string(), "x=y".to_string())));
+        assert_eq!(header.variables[0].file, "d.raw");
+        assert_eq!(header.variables[0].skip, 2);
+        assert_eq!(header.to_string().parse::<FldHeader>().unwrap(), header);
+    }
+
+    #[test]
+    fn strict_parse() {
+        let canonical = format!("# AVS\n{}", SCALAR);
+        assert!(FldHeader::parse_strict(&canonical).is_ok());
+        for bad in &["ndim=1\ndim1=4\ndata=byte # c\nfield=uniform\n",
+                     "ndim=1\ndim1=4\nDATA=byte\nfield=uniform\n",
+                     "ndim=1\ndim1=4\ndata=byte\nfield=uniform\ngarbage\n"] {
+            assert!(bad.parse::<FldHeader>().is_ok(), "{}", bad);
+            assert!(FldHeader::parse_strict(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn file_names_round_trip() {
+        let mut header = FldHeader::new(&[4], DataType::Byte);
+        header.variables.push(VariableFile::new(1, "run #1.raw"));
+        let parsed: FldHeader = header.to_string().parse().unwrap();
+        assert_eq!(parsed.variables, header.variables);
+    }
+
+    #[test]
+    fn huge_ndim_is_missing_a_dim() {
+        let text = "ndim=100000000000000\ndim1=2\ndata=byte\nfield=uniform\n";
+        match text.parse::<FldHeader>() {
+            Err(Error::Missing { key }) => assert_eq!(key, "dim2"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_file_options() {
+        let text = format!("{}variable 1 file=d.raw skp=512 Skip=4\n", SCALAR);
+        let header: FldHeader = text.parse().unwrap();
+        let var = &header.variables[0];
+        assert_eq!((var.skip, var.unknown.clone()),
+                   (4, vec!["skp=512".to_string()]));
+        assert_eq!(header.to_string().parse::<FldHeader>().unwrap(), header);
+        let text = format!("{}variable 1 file=d.raw skp=512\n", SCALAR);
+        match FldHeader::parse_strict(&text) {
+            Err(Error::Malformed { line: Some(5), .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn spacing_and_world_coord() {
+        let mut header = FldHeader::new(&[3, 1, 5], DataType::Byte);
+        assert_eq!(header.spacing(), None);
+        header.min_ext = vec![0.0, 2.0, -1.0];
+        header.max_ext = vec![1.0, 2.0, 1.0];
+        assert_eq!(header.spacing(), Some(vec![0.5, 0.0, 0.5]));
+        assert_eq!(header.world_coord(&[2, 0, 1]),
+                   Some(vec![1.0, 2.0, -0.5]));
+        assert_eq!(header.world_coord(&[3, 0, 0]), None);
+        assert_eq!(header.world_coord(&[0, 1, 0]), None);
+        assert_eq!(header.world_coord(&[0, 0]), None);
+
+        header.max_ext.pop();
+        assert_eq!(header.spacing(), None);
+        header.max_ext.push(1.0);
+        header.field = FieldType::Rectilinear;
+        assert_eq!(header.spacing(), None);
+        assert_eq!(header.world_coord(&[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn extra_lines_keep_their_place() {
+        let text = "# AVS\nndim=1\n# provenance\ndim1=2\ndata=byte # native\n\
+                    field=uniform\ngit=abc\nveclen=1\n# trailer\n";
+        let header: FldHeader = text.parse().unwrap();
+        let written = "# AVS\nndim=1\n# provenance\nveclen=1\nnspace=1\n\
+                       field=uniform\ngit=abc\ndata=byte\n# native\ndim1=2\n\
+                       # trailer\n";
+        assert_eq!(header.to_string(), written);
+        let parsed: FldHeader = written.parse().unwrap();
+        assert_eq!(parsed.to_string(), written);
+        assert_eq!(FldHeader::parse_strict(written).unwrap(), parsed);
+
+        // lines after an entry that is not written follow the known entries
+        let mut header = FldHeader::new(&[2], DataType::Byte);
+        header.extra.clear();
+        header.add_entry("end", "1").unwrap();
+        header.extra.push((Place::After("min_ext".to_string()),
+                           ExtraLine::Comment(" extents".to_string())));
+        header.extra.push((Place::After("ndim".to_string()),
+                           ExtraLine::Comment(" one axis".to_string())));
+        assert_eq!(header.to_string(),
+                   "ndim=1\n# one axis\nveclen=1\nnspace=1\nfield=uniform\n\
+                    data=byte\ndim1=2\n# extents\nend=1\n");
+    }
+
+    #[test]
+    fn parse_errors_carry_line_numbers() {
+        match parse_err("# c\nndim=1\ndim1=x\n") {
+            Error::Parse { line: Some(3), ref key, .. } => assert_eq!(key, "dim1"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn header_without_separator() {
+        // inline data needs the separator
+        match FldHeader::from_bytes(SCALAR.as_bytes()) {
+            Err(Error::Malformed { .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        // a header-only file with external data does not
+        let text = format!("{}variable 1 file=data.raw", SCALAR);
+        let (header, offset) = FldHeader::from_bytes(text.as_bytes()).unwrap();
+        assert_eq!(header.variables[0].file, "data.raw");
+        assert_eq!(offset, text.len());
+    }
+
+
+    #[test]
+    fn separator_may_follow_the_last_line_directly() {
+        let bytes = b"ndim=1\ndim1=1\ndata=byte\nfield=uniform\x0c\x0c\x05";
+        let (header, offset) = FldHeader::from_bytes(bytes).unwrap();
+        assert_eq!(header.field, FieldType::Uniform);
+        assert_eq!(offset, bytes.len() - 1);
+    }
+
+
+    #[test]
+    fn strict_file_options_must_be_lowercase() {
+        let text = format!("{}variable 1 file=d.txt FILETYPE=ascii\n", SCALAR);
+        let header: FldHeader = text.parse().unwrap();
+        assert_eq!(header.variables[0].filetype, FileType::Ascii);
+        match FldHeader::parse_strict(&text) {
+            Err(Error::Malformed { line: Some(5), reason }) =>
+                assert_eq!(reason,
+                           "file option \"FILETYPE\" must be lowercase"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let text = format!("{}variable 1 file=d.txt filetype=ASCII\n", SCALAR);
+        assert!(FldHeader::parse_strict(&text).is_err());
+    }
+
+    #[test]
+    fn from_bytes_reports_payload_offset() {
+        let header = FldHeader::new(&[3], DataType::Byte);
+        let mut bytes = format!("{}\x0c\x0c", header).into_bytes();
+        let end = bytes.len();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let (parsed, offset) = FldHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(offset, end);
+    }
+}
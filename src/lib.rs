@@ -1,86 +1,266 @@
 use std::fs::{File};
-use std::string::String;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::convert::{From, AsRef};
-use std::io::{Read, BufReader, Write};
+use std::io::{Read, BufReader, BufWriter, Write};
 use std::vec::Vec;
-use std::num::ParseIntError;
-use std::mem;
 
-#[derive(Debug)]
-pub enum Error {
-    IO,
-    Parse,
-    DataType,
-    FieldType,
-    Malformed,
-    NotImplemented
+mod element;
+mod error;
+mod header;
+mod writer;
+
+pub use element::FldElement;
+pub use error::Error;
+pub use header::{ExtraLine, FileType, FldHeader, Place, VariableFile};
+pub use writer::FldWriter;
+
+/// Byte order of multi-byte values in a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    /// Big-endian, also used by the `xdr_` types.
+    Big,
+    /// Whatever the reading or writing machine uses.
+    Native,
 }
 
-impl From<std::io::Error> for Error {
-    fn from(_: std::io::Error) -> Error {
-        Error::IO
+impl ByteOrder {
+    /// The concrete byte order of this machine.
+    pub fn host() -> ByteOrder {
+        if cfg!(target_endian = "big") {
+            ByteOrder::Big
+        } else {
+            ByteOrder::Little
+        }
     }
 }
 
-impl From<ParseIntError> for Error {
-    fn from(_: ParseIntError) -> Error {
-        Error::Parse
+/// The in-memory type behind a `DataType`, independent of byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scalar {
+    U8,
+    I8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    /// True if every value of `self` is exactly representable as `to`.
+    fn widens_to(self, to: Scalar) -> bool {
+        use Scalar::*;
+        match (self, to) {
+            _ if self == to => true,
+            (U8, I16) | (U8, U16) | (U8, I32) | (U8, U32) => true,
+            (I8, I16) | (I8, I32) => true,
+            (I16, I32) | (U16, I32) | (U16, U32) => true,
+            (U8, F32) | (I8, F32) | (I16, F32) | (U16, F32) => true,
+            (_, F64) => true,
+            _ => false,
+        }
     }
 }
 
-#[derive(Debug)]
+/// The `data=` type of a payload.
+///
+/// Unsuffixed names (`short`, `int`, `float`, ...) are in the byte order of
+/// the machine; `_le`, `_be` and `xdr_` names fix it.  `char` is a signed
+/// byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataType {
-    XDRFloat,
-    FloatLE,
     Byte,
+    Char,
+    Short,
+    ShortLE,
+    ShortBE,
+    XDRShort,
+    UShort,
+    UShortLE,
+    UShortBE,
+    Int,
+    IntLE,
+    IntBE,
+    XDRInt,
+    UInt,
+    UIntLE,
+    UIntBE,
+    Float,
+    FloatLE,
+    FloatBE,
+    XDRFloat,
+    Double,
+    DoubleLE,
+    DoubleBE,
+    XDRDouble,
 }
 
 impl DataType {
-    fn from_str(s: &str) -> Result<DataType, Error> {
+    pub(crate) fn from_str(s: &str) -> Result<DataType, Error> {
         match s {
+            "byte" => Ok(DataType::Byte),
+            "char" => Ok(DataType::Char),
+            "short" => Ok(DataType::Short),
+            "short_le" => Ok(DataType::ShortLE),
+            "short_be" => Ok(DataType::ShortBE),
+            "xdr_short" => Ok(DataType::XDRShort),
+            "ushort" => Ok(DataType::UShort),
+            "ushort_le" => Ok(DataType::UShortLE),
+            "ushort_be" => Ok(DataType::UShortBE),
+            "int" | "integer" => Ok(DataType::Int),
+            "int_le" => Ok(DataType::IntLE),
+            "int_be" => Ok(DataType::IntBE),
+            "xdr_int" | "xdr_integer" => Ok(DataType::XDRInt),
+            "uint" => Ok(DataType::UInt),
+            "uint_le" => Ok(DataType::UIntLE),
+            "uint_be" => Ok(DataType::UIntBE),
+            "float" => Ok(DataType::Float),
             "float_le" => Ok(DataType::FloatLE),
+            "float_be" => Ok(DataType::FloatBE),
             "xdr_float" => Ok(DataType::XDRFloat),
-            "byte" => Ok(DataType::Byte),
-            _ => Err(Error::DataType)
+            "double" => Ok(DataType::Double),
+            "double_le" => Ok(DataType::DoubleLE),
+            "double_be" => Ok(DataType::DoubleBE),
+            "xdr_double" => Ok(DataType::XDRDouble),
+            _ => Err(Error::DataType { line: None, value: s.to_string() })
         }
     }
 
-    fn num_bytes(self: &Self) -> usize {
+    /// The token used for this type on the header's `data=` line.
+    pub fn as_str(&self) -> &'static str {
         match *self {
-            DataType::XDRFloat => 4usize,
-            DataType::FloatLE => 4usize,
-            DataType::Byte => 1usize,
+            DataType::Byte => "byte",
+            DataType::Char => "char",
+            DataType::Short => "short",
+            DataType::ShortLE => "short_le",
+            DataType::ShortBE => "short_be",
+            DataType::XDRShort => "xdr_short",
+            DataType::UShort => "ushort",
+            DataType::UShortLE => "ushort_le",
+            DataType::UShortBE => "ushort_be",
+            DataType::Int => "int",
+            DataType::IntLE => "int_le",
+            DataType::IntBE => "int_be",
+            DataType::XDRInt => "xdr_int",
+            DataType::UInt => "uint",
+            DataType::UIntLE => "uint_le",
+            DataType::UIntBE => "uint_be",
+            DataType::Float => "float",
+            DataType::FloatLE => "float_le",
+            DataType::FloatBE => "float_be",
+            DataType::XDRFloat => "xdr_float",
+            DataType::Double => "double",
+            DataType::DoubleLE => "double_le",
+            DataType::DoubleBE => "double_be",
+            DataType::XDRDouble => "xdr_double",
         }
     }
 
-    fn convert_to_f32(self: &Self, buf: &[u8]) -> f32 {
+    pub(crate) fn scalar(&self) -> Scalar {
         match *self {
-            DataType::XDRFloat => {
-                let b = [buf[3], buf[2], buf[1], buf[0]];
-                unsafe { mem::transmute(b) }
-            },
-            DataType::FloatLE => {
-                let b = [buf[0], buf[1], buf[2], buf[3]];
-                unsafe { mem::transmute(b) }
-            },
-            DataType::Byte => {
-                buf[0] as f32
-            },
+            DataType::Byte => Scalar::U8,
+            DataType::Char => Scalar::I8,
+            DataType::Short | DataType::ShortLE | DataType::ShortBE
+                | DataType::XDRShort => Scalar::I16,
+            DataType::UShort | DataType::UShortLE | DataType::UShortBE
+                => Scalar::U16,
+            DataType::Int | DataType::IntLE | DataType::IntBE
+                | DataType::XDRInt => Scalar::I32,
+            DataType::UInt | DataType::UIntLE | DataType::UIntBE
+                => Scalar::U32,
+            DataType::Float | DataType::FloatLE | DataType::FloatBE
+                | DataType::XDRFloat => Scalar::F32,
+            DataType::Double | DataType::DoubleLE | DataType::DoubleBE
+                | DataType::XDRDouble => Scalar::F64,
+        }
+    }
+
+    /// The byte order of values of this type.  Single-byte types report
+    /// `ByteOrder::Native`.
+    pub fn byte_order(&self) -> ByteOrder {
+        match *self {
+            DataType::ShortLE | DataType::UShortLE | DataType::IntLE
+                | DataType::UIntLE | DataType::FloatLE | DataType::DoubleLE
+                => ByteOrder::Little,
+            DataType::ShortBE | DataType::XDRShort | DataType::UShortBE
+                | DataType::IntBE | DataType::XDRInt | DataType::UIntBE
+                | DataType::FloatBE | DataType::XDRFloat
+                | DataType::DoubleBE | DataType::XDRDouble
+                => ByteOrder::Big,
+            _ => ByteOrder::Native,
+        }
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        match self.scalar() {
+            Scalar::U8 | Scalar::I8 => 1usize,
+            Scalar::I16 | Scalar::U16 => 2usize,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4usize,
+            Scalar::F64 => 8usize,
+        }
+    }
+
+    /// True if values of this type convert to `other` without loss.
+    pub fn converts_losslessly_to(&self, other: DataType) -> bool {
+        self.scalar().widens_to(other.scalar())
+    }
+
+    /// Decodes one value from the front of `buf`.  Every supported type is
+    /// represented exactly by an `f64`.
+    fn convert_to_f64(&self, buf: &[u8]) -> f64 {
+        macro_rules! decode {
+            ($t:ty, $n:expr, $order:expr) => {{
+                let mut b = [0u8; $n];
+                b.copy_from_slice(&buf[.. $n]);
+                let v = match $order {
+                    ByteOrder::Little => <$t>::from_le_bytes(b),
+                    ByteOrder::Big => <$t>::from_be_bytes(b),
+                    ByteOrder::Native => <$t>::from_ne_bytes(b),
+                };
+                f64::from(v)
+            }}
+        }
+        let order = self.byte_order();
+        match self.scalar() {
+            Scalar::U8 => f64::from(buf[0]),
+            Scalar::I8 => f64::from(buf[0] as i8),
+            Scalar::I16 => decode!(i16, 2, order),
+            Scalar::U16 => decode!(u16, 2, order),
+            Scalar::I32 => decode!(i32, 4, order),
+            Scalar::U32 => decode!(u32, 4, order),
+            Scalar::F32 => decode!(f32, 4, order),
+            Scalar::F64 => decode!(f64, 8, order),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FieldType {
-    Uniform
+    Uniform,
+    /// Axis-aligned grid with per-axis coordinates in `coord N` files.
+    Rectilinear,
+    /// Arbitrary grid; `coord N` holds coordinate N of every grid point.
+    Irregular,
 }
 
 impl FieldType {
-    fn from_str(s: &str) -> Result<FieldType, Error> {
+    pub(crate) fn from_str(s: &str) -> Result<FieldType, Error> {
         match s {
             "uniform" => Ok(FieldType::Uniform),
-            _ => Err(Error::FieldType)
+            "rectilinear" => Ok(FieldType::Rectilinear),
+            "irregular" => Ok(FieldType::Irregular),
+            _ => Err(Error::FieldType { line: None, value: s.to_string() })
+        }
+    }
+
+    /// The token used for this type on the header's `field=` line.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FieldType::Uniform => "uniform",
+            FieldType::Rectilinear => "rectilinear",
+            FieldType::Irregular => "irregular",
         }
     }
 }
@@ -90,154 +270,894 @@ pub struct AVSFile {
     pub sizes: Vec<usize>,
     pub data_type: DataType,
     pub field_type: FieldType,
-    reader: Box<Read>
+    /// The full header; `ndim`, `sizes`, `data_type` and `field_type` are
+    /// copies of its `ndim`, `dims`, `data` and `field`.
+    pub header: FldHeader,
+    /// Resolved paths of the external files, in `header.variables` order.
+    data_paths: Vec<PathBuf>,
+    /// One reader per entry of `header.variables`, or a single reader for
+    /// the inline payload.
+    readers: Vec<Box<dyn Read>>,
+    coords: Vec<Vec<f32>>,
+}
+
+/// Resolves an external file named in the header at `fld_path`; relative
+/// names are taken relative to the header's directory.
+fn resolve_path(fld_path: &Path, file: &str) -> PathBuf {
+    let path = Path::new(file);
+    match fld_path.parent() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// One payload stream, read into memory and checked to hold every value the
+/// header calls for before any of them is decoded.
+struct Payload {
+    data_type: DataType,
+    count: usize,
+    values: Values,
+}
+
+enum Values {
+    /// Value i is the `data_type.num_bytes()` bytes at `first + i*step`.
+    Binary { bytes: Vec<u8>, first: usize, step: usize },
+    /// Value i is word `offset + i*stride` after the first `skip` lines.
+    Ascii { text: String, skip: usize, offset: usize, stride: usize },
+}
+
+/// The words of an ascii payload that belong to a variable, in order.
+fn ascii_words<'a>(text: &'a str, skip: usize, offset: usize, stride: usize)
+        -> impl Iterator<Item = &'a str> + 'a {
+    text.lines()
+        .skip(skip)
+        .flat_map(|line| line.split_whitespace())
+        .skip(offset)
+        .step_by(stride)
+}
+
+impl Payload {
+    /// Reads `count` values of `data_type` from `reader`, laid out as `var`
+    /// describes (or contiguously, for an inline payload).
+    fn read(reader: &mut dyn Read, data_type: DataType,
+            var: Option<&VariableFile>, count: usize)
+                -> Result<Payload, Error> {
+        let (filetype, skip, offset, stride) = match var {
+            Some(v) => (v.filetype, v.skip, v.offset, v.stride),
+            None => (FileType::Binary, 0, 0, 1),
+        };
+        let values = match filetype {
+            FileType::Binary => {
+                let n = data_type.num_bytes();
+                let mut bytes = Vec::<u8>::new();
+                reader.read_to_end(&mut bytes)?;
+                // value i occupies the n bytes at first + i*step; skip,
+                // offset and stride come from the file, so guard against
+                // overflow
+                let first = offset.checked_mul(n)
+                    .and_then(|o| o.checked_add(skip));
+                let step = stride.checked_mul(n);
+                let rest = first
+                    .and_then(|first| bytes.len().checked_sub(first))
+                    .and_then(|rest| rest.checked_sub(n));
+                let found = match (rest, step) {
+                    (None, _) => 0,
+                    (Some(rest), Some(step)) => rest/step + 1,
+                    (Some(_), None) => 1,
+                };
+                if found < count {
+                    return Err(Error::Truncated { expected: count, found });
+                }
+                Values::Binary {
+                    bytes,
+                    first: first.unwrap_or(0),
+                    step: step.unwrap_or(0),
+                }
+            },
+            FileType::Ascii => {
+                let mut text = String::new();
+                reader.read_to_string(&mut text)?;
+                let found = ascii_words(&text, skip, offset, stride)
+                    .take(count)
+                    .count();
+                if found < count {
+                    return Err(Error::Truncated { expected: count, found });
+                }
+                Values::Ascii { text, skip, offset, stride }
+            },
+        };
+        Ok(Payload { data_type, count, values })
+    }
+
+    /// Decodes the values, passing each one and its index to `f`.
+    fn decode<F>(&self, f: &mut F) -> Result<(), Error>
+            where F: FnMut(usize, f64) -> Result<(), Error> {
+        match self.values {
+            Values::Binary { ref bytes, first, step } => {
+                let n = self.data_type.num_bytes();
+                for index in 0..self.count {
+                    let off0 = first + index*step;
+                    f(index, self.data_type.convert_to_f64(
+                        &bytes[off0 .. off0 + n]))?;
+                }
+            },
+            Values::Ascii { ref text, skip, offset, stride } => {
+                let words = ascii_words(text, skip, offset, stride)
+                    .take(self.count);
+                for (index, word) in words.enumerate() {
+                    f(index, Error::parse(&format!("value {}", index), word)?)?;
+                }
+            },
+        }
+        Ok(())
+    }
 }
 
 impl AVSFile {
-    pub fn write<W: Write, T>(
+    /// Writes a scalar uniform field whose `data=` type matches `T`, in this
+    /// machine's byte order.  See `FldWriter` for more options.
+    pub fn write<W: Write, T: FldElement>(
                 writer: &mut W, dims: &[usize], data: &[T]) 
                     -> Result<(), Error> {
-        // header
-        let ndim = dims.len();
-        try!(writer.write_fmt(format_args!("# AVS FLD file (written by avsfldrs github.com/greyhill/avsfldrs)\n")));
-        try!(writer.write_fmt(format_args!("ndim={}\n", ndim)));
-        try!(writer.write_fmt(format_args!("veclen=1\n")));
-        try!(writer.write_fmt(format_args!("nspace={}\n", ndim)));
-        try!(writer.write_fmt(format_args!("field=uniform\n")));
-        try!(writer.write_fmt(format_args!("data=float_le\n"))); // TODO
-        for (id, size) in dims.iter().enumerate() {
-            try!(writer.write_fmt(format_args!("dim{}={}\n", id+1, size)));
-        }
-        try!(writer.write_fmt(format_args!("{}{}", 12 as char, 12 as char)));
-        let b: &[u8] = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const u8, 
-                                       data.len()*mem::size_of::<T>())
-        };
-        try!(writer.write_all(b));
-        Ok(())
+        FldWriter::new(dims).write(writer, data)
     }
 
-    pub fn read_to_f32(self: &mut Self) -> Result<Vec<f32>, Error> {
-        let size = self.sizes.iter().fold(1 as usize, |l, r| l * *r);
-        let mut buf_u8 = Vec::<u8>::with_capacity(size * self.data_type.num_bytes());
-        let mut buf_tr = Vec::<f32>::with_capacity(size);
-        try!(self.reader.read_to_end(&mut buf_u8));
+    /// Like `write`, but stores the payload in `order`.  `ByteOrder::Big`
+    /// produces `xdr_` types where AVS defines one.
+    pub fn write_with_order<W: Write, T: FldElement>(
+                writer: &mut W, dims: &[usize], data: &[T], order: ByteOrder)
+                    -> Result<(), Error> {
+        FldWriter::new(dims).byte_order(order).write(writer, data)
+    }
 
-        for n in 0 .. size {
-            let off0 = n*self.data_type.num_bytes();
-            let off1 = (n+1)*self.data_type.num_bytes();
-            buf_tr.push(self.data_type.convert_to_f32(&buf_u8[off0 .. off1]));
+    /// Writes `header` followed by `data`; for vector fields `data` holds
+    /// `header.veclen` interleaved components per grid point.
+    ///
+    /// `header.data` must describe `T`; values are byte-swapped to the order
+    /// it names.  The header may not name `variable` or `coord` files; use
+    /// `create` or `create_with_coords` to write those.
+    pub fn write_with_header<W: Write, T: FldElement>(
+                writer: &mut W, header: &FldHeader, data: &[T])
+                    -> Result<(), Error> {
+        header.check()?;
+        if !header.variables.is_empty() {
+            return Err(Error::Invalid(
+                "write_with_header cannot write variable files; use create"
+                    .to_string()));
         }
+        if !header.coords.is_empty() {
+            return Err(Error::Invalid(
+                "write_with_header cannot write coord files; \
+                 use create_with_coords".to_string()));
+        }
+        AVSFile::check_len(header, data)?;
+        AVSFile::write_inline(writer, header, data)
+    }
 
-        Ok(buf_tr)
+    /// Writes `header` followed by the separator and `data`, unchecked.
+    fn write_inline<W: Write, T: FldElement>(
+                writer: &mut W, header: &FldHeader, data: &[T])
+                    -> Result<(), Error> {
+        writer.write_fmt(format_args!("{}", header))?;
+        writer.write_fmt(format_args!("{}{}", 12 as char, 12 as char))?;
+        AVSFile::write_payload(
+            writer, header.data, header.veclen, data, FileType::Binary)
     }
 
-    pub fn read<T>(self: &mut Self) -> Result<Vec<T>, Error> {
-        let size = self.sizes.iter().fold(1 as usize, |l, r| l * *r);
-        let mut buf_u8 = Vec::<u8>::with_capacity(mem::size_of::<T>()*size);
-        try!(self.reader.read_to_end(&mut buf_u8));
-        let buf: Vec<T> = unsafe {
-            let ptr = buf_u8.as_mut_ptr();
-            let cap = buf_u8.capacity();
-            mem::forget(buf_u8);
-            Vec::<T>::from_raw_parts(
-                mem::transmute(ptr),
-                size,
-                cap / mem::size_of::<T>())
-        };
-        Ok(buf)
+    /// Writes `header` to a new file at `path`.  If `header` names external
+    /// `variable` files, `data` is written to them in their `filetype`,
+    /// relative to the directory of `path`: a single file receives every
+    /// component, otherwise each file receives its own component.  The files
+    /// must not use `skip`, `offset` or `stride`.  Without external files
+    /// `data` follows the header inline as in `write_with_header`.
+    pub fn create<P: AsRef<Path>, T: FldElement>(
+                path: &P, header: &FldHeader, data: &[T])
+                    -> Result<(), Error> {
+        AVSFile::create_with_coords(path, header, data, &[])
     }
 
-    pub fn open<P: AsRef<Path>>(p: &P) -> Result<AVSFile, Error> {
-        let path = p.as_ref();
-        let mut reader = BufReader::new(try!(File::open(path)));
-
-        let mut ndim: Option<usize> = None;
-        let mut sizes = Vec::<Option<usize>>::new();
-        let mut data_type: Option<DataType> = None;
-        let mut field_type: Option<FieldType> = None;
-        let mut external: Option<String> = None;
-
-        let mut line = String::new();
-        let mut last_char: u8 = 0;
-        loop {
-            let mut new_char_buf: [u8;1] = [ 0u8 ];
-            try!(reader.read(&mut new_char_buf));
-
-            // break on two chr 14s
-            let new_char = new_char_buf[0];
-            if (new_char, last_char) == (12u8, 12u8) {
-                break;
-            }
-            last_char = new_char;
-
-            line.push(new_char as char);
-
-            // new line; process the line and discard
-            if new_char == 10 {
-                let tokens: Vec<&str> = line.split('=')
-                    .map(|s| s.trim()).collect();
-                match tokens[0] {
-                    "ndim" => {
-                        let nd = try!(tokens[1].parse::<usize>());
-                        ndim = Some(nd);
-                        for _ in 0..nd {
-                            sizes.push(None);
-                        }
-                    },
-                    "dim1" => sizes[0] = Some(try!(tokens[1].parse::<usize>())),
-                    "dim2" => sizes[1] = Some(try!(tokens[1].parse::<usize>())),
-                    "dim3" => sizes[2] = Some(try!(tokens[1].parse::<usize>())),
-                    "dim4" => sizes[3] = Some(try!(tokens[1].parse::<usize>())),
-                    "dim5" => sizes[4] = Some(try!(tokens[1].parse::<usize>())),
-                    "dim6" => sizes[5] = Some(try!(tokens[1].parse::<usize>())),
-                    "dim7" => sizes[6] = Some(try!(tokens[1].parse::<usize>())),
-                    "data" => 
-                        data_type = Some(try!(DataType::from_str(tokens[1]))),
-                    "field" => 
-                        field_type = Some(try!(FieldType::from_str(tokens[1]))),
-                    "variable 1 file" => 
-                        external = Some(tokens[1].to_string()),
-                    _ => {}
-                }
-            }
-            // hack?  code smell?  need borrow in previous block to expire
-            if new_char == 10 {
-                line.clear();
-            }
+    /// Like `create`, but also writes `coords[N-1]` to the `coord N` file of
+    /// `header`: the positions along axis N of a rectilinear field, or
+    /// coordinate N of every grid point of an irregular one.
+    pub fn create_with_coords<P: AsRef<Path>, T: FldElement>(
+                path: &P, header: &FldHeader, data: &[T], coords: &[Vec<f32>])
+                    -> Result<(), Error> {
+        let path = path.as_ref();
+        AVSFile::create_path(path, header, data, coords)
+            .map_err(|e| e.in_file(path))
+    }
+
+    fn create_path<T: FldElement>(
+                path: &Path, header: &FldHeader, data: &[T], coords: &[Vec<f32>])
+                    -> Result<(), Error> {
+        header.check()?;
+        AVSFile::check_len(header, data)?;
+        header.check_coords(coords)?;
+        for entry in &header.coords {
+            AVSFile::write_external(path, entry, header.coord_type(), 1,
+                                    &coords[entry.index - 1])?;
         }
 
-        match external {
-            None => {
-                let mut tr = AVSFile { 
-                    ndim: try!(ndim.ok_or(Error::Malformed)),
-                    sizes: Vec::<usize>::new(),
-                    data_type: try!(data_type.ok_or(Error::Malformed)),
-                    field_type: try!(field_type.ok_or(Error::Malformed)),
-                    reader: Box::new(reader),
-                };
-                for idx in 0..ndim.unwrap() {
-                    tr.sizes.push(
-                        try!(sizes[idx].ok_or(Error::Malformed)));
+        let mut writer = BufWriter::new(File::create(path)?);
+        if header.variables.is_empty() {
+            return AVSFile::write_inline(&mut writer, header, data);
+        }
+        writer.write_fmt(format_args!("{}", header))?;
+        writer.write_fmt(format_args!("{}{}", 12 as char, 12 as char))?;
+        writer.flush()?;
+
+        if header.variables.len() == 1 {
+            return AVSFile::write_external(path, &header.variables[0],
+                                           header.data, header.veclen, data);
+        }
+        for var in &header.variables {
+            let values: Vec<T> = data.iter()
+                .skip(var.index - 1)
+                .step_by(header.veclen)
+                .cloned()
+                .collect();
+            AVSFile::write_external(path, var, header.data, 1, &values)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that `data` holds exactly the values `header` describes.
+    fn check_len<T>(header: &FldHeader, data: &[T]) -> Result<(), Error> {
+        let expected = header.checked_num_values()?;
+        if data.len() != expected {
+            return Err(Error::SizeMismatch { expected, found: data.len() });
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to the external file `entry` of the header at `fld_path`.
+    fn write_external<T: FldElement>(
+                fld_path: &Path, entry: &VariableFile, data_type: DataType,
+                veclen: usize, data: &[T]) -> Result<(), Error> {
+        if (entry.skip, entry.offset, entry.stride) != (0, 0, 1) {
+            return Err(Error::NotImplemented(
+                "writing external files with skip, offset or stride"));
+        }
+        let data_path = resolve_path(fld_path, &entry.file);
+        File::create(&data_path)
+            .map_err(Error::from)
+            .and_then(|file| AVSFile::write_payload(
+                &mut BufWriter::new(file), data_type, veclen, data,
+                entry.filetype))
+            .map_err(|e| e.in_file(&data_path))
+    }
+
+    /// Writes `data` as `data_type` values, `veclen` to a grid point.
+    fn write_payload<W: Write, T: FldElement>(
+                writer: &mut W, data_type: DataType, veclen: usize,
+                data: &[T], filetype: FileType) -> Result<(), Error> {
+        let element = T::data_type(ByteOrder::Native);
+        if data_type.scalar() != element.scalar() {
+            return Err(Error::TypeMismatch { header: data_type, element });
+        }
+        match filetype {
+            FileType::Binary => {
+                let order = data_type.byte_order();
+                let mut b = Vec::<u8>::with_capacity(
+                    data.len() * data_type.num_bytes());
+                for v in data {
+                    v.encode(order, &mut b);
                 }
-                Ok(tr)
+                writer.write_all(&b)?;
             },
-            Some(path) => {
-                let new_reader = BufReader::new(try!(File::open(&path)));
-                let mut tr = AVSFile { 
-                    ndim: try!(ndim.ok_or(Error::Malformed)),
-                    sizes: Vec::<usize>::new(),
-                    data_type: try!(data_type.ok_or(Error::Malformed)),
-                    field_type: try!(field_type.ok_or(Error::Malformed)),
-                    reader: Box::new(new_reader),
-                };
-                for idx in 0..ndim.unwrap() {
-                    tr.sizes.push(
-                        try!(sizes[idx].ok_or(Error::Malformed)));
+            FileType::Ascii => {
+                // one grid point per line
+                for point in data.chunks(veclen) {
+                    let line = point.iter()
+                        .map(|v| v.to_string())
+                        .collect::<Vec<String>>()
+                        .join(" ");
+                    writer.write_fmt(format_args!("{}\n", line))?;
                 }
-                Ok(tr)
             },
         }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The external file holding the payload (or its first variable), if
+    /// it is not inline.
+    pub fn data_path(&self) -> Option<&Path> {
+        self.data_paths.first().map(|p| p.as_path())
+    }
+
+    /// The external files holding the payload, in `header.variables` order.
+    pub fn data_paths(&self) -> &[PathBuf] {
+        &self.data_paths
+    }
+
+    /// The coordinates read from the `coord N` files.  For a rectilinear
+    /// field, `coords()[N-1]` holds the `dims[N-1]` positions along axis N;
+    /// for an irregular field it holds coordinate N of each grid point, so
+    /// the result is `nspace` by `num_points()`.  Uniform fields have none.
+    pub fn coords(&self) -> &[Vec<f32>] {
+        &self.coords
+    }
+
+    /// The number of components stored at each grid point.
+    pub fn veclen(&self) -> usize {
+        self.header.veclen
+    }
+
+    /// Decodes the payload, passing each value and its index in the
+    /// interleaved payload to `f`.  Values of a split vector field arrive one
+    /// component at a time.  Every file is read and found long enough before
+    /// `f` is first called.
+    fn for_each_value<F>(&mut self, mut f: F) -> Result<(), Error>
+            where F: FnMut(usize, f64) -> Result<(), Error> {
+        let data_type = self.header.data;
+        let count = self.header.checked_num_values()?;
+        if self.header.variables.len() <= 1 {
+            let result = Payload::read(&mut *self.readers[0], data_type,
+                                       self.header.variables.first(), count)
+                .and_then(|payload| payload.decode(&mut f));
+            return match self.data_paths.first() {
+                Some(path) => result.map_err(|e| e.in_file(path)),
+                None => result,
+            };
+        }
+
+        let veclen = self.header.veclen;
+        let count = self.header.num_points();
+        let mut payloads = Vec::new();
+        for ((var, reader), path) in self.header.variables.iter()
+                .zip(self.readers.iter_mut())
+                .zip(self.data_paths.iter()) {
+            payloads.push(Payload::read(&mut **reader, data_type, Some(var),
+                                        count)
+                .map_err(|e| e.in_file(path))?);
+        }
+        for ((var, payload), path) in self.header.variables.iter()
+                .zip(payloads.iter())
+                .zip(self.data_paths.iter()) {
+            let component = var.index - 1;
+            payload.decode(&mut |index, v| f(index*veclen + component, v))
+                .map_err(|e| e.in_file(path))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the payload into a vector, converting each value and its index
+    /// with `convert`.
+    fn read_into<T: Copy, F>(&mut self, zero: T, mut convert: F)
+            -> Result<Vec<T>, Error>
+            where F: FnMut(usize, f64) -> Result<T, Error> {
+        let count = self.header.checked_num_values()?;
+        let mut values = Vec::new();
+        self.for_each_value(|index, v| {
+            // allocate only once the payload is known to hold every value
+            if values.is_empty() {
+                values = vec![zero; count];
+            }
+            values[index] = convert(index, v)?;
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    /// Reads the payload, converting each value to `f32`.  Vector fields
+    /// yield `veclen` interleaved values per grid point.
+    pub fn read_to_f32(&mut self) -> Result<Vec<f32>, Error> {
+        self.read_into(0f32, |_, v| Ok(v as f32))
+    }
+
+    /// Reads the payload as one `veclen`-long vector per grid point.
+    pub fn read_vectors_f32(&mut self) -> Result<Vec<Vec<f32>>, Error> {
+        let veclen = self.header.veclen;
+        let values = self.read_to_f32()?;
+        Ok(values.chunks(veclen).map(|v| v.to_vec()).collect())
+    }
+
+    /// Reads the payload as `T`, honouring the file's byte order.  `T` must
+    /// be the file's element type or one it converts to losslessly (e.g.
+    /// `i32` for a `short` file); otherwise `Error::TypeMismatch` is returned.
+    pub fn read_as<T: FldElement>(&mut self) -> Result<Vec<T>, Error> {
+        let data_type = self.header.data;
+        let element = T::data_type(ByteOrder::Native);
+        if !data_type.converts_losslessly_to(element) {
+            return Err(Error::TypeMismatch { header: data_type, element });
+        }
+        self.read_into(T::from_f64(0.0), |_, v| Ok(T::from_f64(v)))
+    }
+
+    /// Reads the payload, converting each value to `T`.  Unlike `read_as`,
+    /// any file type is accepted; a value that does not fit in `T` gives
+    /// `Error::OutOfRange`, and fractions are truncated for integer `T`.
+    pub fn read_converted<T: FldElement>(&mut self) -> Result<Vec<T>, Error> {
+        self.read_into(T::from_f64(0.0), |index, value| {
+            T::try_from_f64(value).ok_or(Error::OutOfRange { index, value })
+        })
+    }
+
+    pub fn read_to_f64(&mut self) -> Result<Vec<f64>, Error> {
+        self.read_converted::<f64>()
+    }
+
+    pub fn read_to_i32(&mut self) -> Result<Vec<i32>, Error> {
+        self.read_converted::<i32>()
+    }
+
+    pub fn read_to_u8(&mut self) -> Result<Vec<u8>, Error> {
+        self.read_converted::<u8>()
+    }
+
+    pub fn open<P: AsRef<Path>>(p: &P) -> Result<AVSFile, Error> {
+        let path = p.as_ref();
+        AVSFile::open_path(path).map_err(|e| e.in_file(path))
+    }
+
+    fn open_path(path: &Path) -> Result<AVSFile, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (header, _) = FldHeader::from_reader(&mut reader)?;
+
+        let coords = AVSFile::read_coords(path, &header)?;
+        if header.variables.is_empty() {
+            return Ok(AVSFile::new(header, Vec::new(), vec![Box::new(reader)],
+                                   coords));
+        }
+
+        let mut data_paths = Vec::<PathBuf>::new();
+        let mut readers = Vec::<Box<dyn Read>>::new();
+        for var in &header.variables {
+            let data_path = resolve_path(path, &var.file);
+            let file = File::open(&data_path)
+                .map_err(|e| Error::from(e).in_file(&data_path))?;
+            readers.push(Box::new(BufReader::new(file)));
+            data_paths.push(data_path);
+        }
+        Ok(AVSFile::new(header, data_paths, readers, coords))
+    }
+
+    fn new(header: FldHeader, data_paths: Vec<PathBuf>,
+           readers: Vec<Box<dyn Read>>, coords: Vec<Vec<f32>>) -> AVSFile {
+        AVSFile {
+            ndim: header.ndim,
+            sizes: header.dims.clone(),
+            data_type: header.data,
+            field_type: header.field,
+            header,
+            data_paths,
+            readers,
+            coords,
+        }
+    }
+
+    /// Reads the `coord N` files of the header at `fld_path`.
+    fn read_coords(fld_path: &Path, header: &FldHeader)
+            -> Result<Vec<Vec<f32>>, Error> {
+        let mut coords = match header.field {
+            FieldType::Uniform => return Ok(Vec::new()),
+            FieldType::Rectilinear => vec![Vec::new(); header.ndim],
+            FieldType::Irregular => vec![Vec::new(); header.nspace],
+        };
+        header.checked_num_values()?;
+        for entry in &header.coords {
+            let count = match header.field {
+                FieldType::Irregular => header.num_points(),
+                _ => header.dims[entry.index - 1],
+            };
+            let data_path = resolve_path(fld_path, &entry.file);
+            let mut values = Vec::new();
+            File::open(&data_path)
+                .map_err(Error::from)
+                .and_then(|file| Payload::read(
+                    &mut BufReader::new(file), header.coord_type(), Some(entry),
+                    count))
+                .and_then(|payload| payload.decode(&mut |_, v| {
+                    values.push(v as f32);
+                    Ok(())
+                }))
+                .map_err(|e| e.in_file(&data_path))?;
+            coords[entry.index - 1] = values;
+        }
+        Ok(coords)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty scratch directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("avsfld-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn inline_round_trip() {
+        let data: Vec<i16> = (0..24).map(|v| v * 100 - 1000).collect();
+        for &order in &[ByteOrder::Little, ByteOrder::Big, ByteOrder::Native] {
+            let dir = temp_dir("inline");
+            let path = dir.join("a.fld");
+            FldWriter::new(&[2, 3, 4]).byte_order(order)
+                .create(&path, &data).unwrap();
+            let mut file = AVSFile::open(&path).unwrap();
+            assert_eq!(file.sizes, vec![2, 3, 4]);
+            assert_eq!(file.data_type, i16::data_type(order));
+            assert_eq!(file.read_as::<i16>().unwrap(), data);
+        }
+    }
+
+    fn read_all(bytes: &[u8], data_type: DataType, var: &VariableFile,
+                count: usize) -> Result<Vec<f64>, Error> {
+        let mut values = Vec::new();
+        Payload::read(&mut &bytes[..], data_type, Some(var), count)?
+            .decode(&mut |_, v| { values.push(v); Ok(()) })?;
+        Ok(values)
+    }
+
+    #[test]
+    fn huge_dims_over_short_payload_are_truncated() {
+        let path = std::env::temp_dir().join("avsfld-huge-dims.fld");
+        let header = FldHeader::new(&[100000000, 100000000], DataType::Byte);
+        std::fs::write(&path, format!("{}\x0c\x0c\x01", header)).unwrap();
+        let mut file = AVSFile::open(&path).unwrap();
+        match file.read_to_f32() {
+            Err(Error::Truncated { expected: 10000000000000000, found: 1 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_with_header_rejects_variable_files() {
+        let mut header = FldHeader::new(&[2], DataType::Byte);
+        header.variables.push(VariableFile::new(1, "data.raw"));
+        match AVSFile::write_with_header(&mut Vec::new(), &header, &[1u8, 2]) {
+            Err(Error::Invalid(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn create_rejects_repeated_variables() {
+        let path = std::env::temp_dir().join("avsfld-repeated.fld");
+        let mut header = FldHeader::new(&[2], DataType::Byte);
+        header.veclen = 2;
+        header.variables.push(VariableFile::new(1, "a.raw"));
+        header.variables.push(VariableFile::new(1, "b.raw"));
+        match AVSFile::create(&path, &header, &[1u8, 2, 3, 4]) {
+            Err(Error::File { source, .. }) => match *source {
+                Error::Malformed { .. } => {},
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn create_rejects_coord_0() {
+        let path = std::env::temp_dir().join("avsfld-coord0.fld");
+        let mut header = FldHeader::new(&[2], DataType::Byte);
+        header.field = FieldType::Rectilinear;
+        header.coords.push(VariableFile::new(0, "x.raw"));
+        match AVSFile::create_with_coords(&path, &header, &[1u8, 2],
+                                          &[vec![0.0, 1.0]]) {
+            Err(Error::File { source, .. }) => match *source {
+                Error::Malformed { .. } => {},
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_skip_offset_stride() {
+        let var = VariableFile { skip: 2, offset: 1, stride: 3,
+                                 ..VariableFile::new(1, "x") };
+        let bytes = [9, 9, 0, 1, 0, 0, 2, 0, 0, 3];
+        assert_eq!(read_all(&bytes, DataType::Byte, &var, 3).unwrap(),
+                   vec![1.0, 2.0, 3.0]);
+        match read_all(&bytes, DataType::Byte, &var, 4) {
+            Err(Error::Truncated { expected: 4, found: 3 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn huge_offset_and_stride_are_truncated() {
+        let var = VariableFile { offset: usize::MAX / 2,
+                                 ..VariableFile::new(1, "x") };
+        match read_all(&[0; 16], DataType::Double, &var, 1) {
+            Err(Error::Truncated { expected: 1, found: 0 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        let var = VariableFile { stride: usize::MAX / 2,
+                                 ..VariableFile::new(1, "x") };
+        match read_all(&[0; 16], DataType::Double, &var, 2) {
+            Err(Error::Truncated { expected: 2, found: 1 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn vector_field() {
+        let dir = temp_dir("vector");
+        let path = dir.join("v.fld");
+        let data: Vec<f32> = (0..12).map(|v| v as f32 / 2.0).collect();
+        FldWriter::new(&[4]).veclen(3).create(&path, &data).unwrap();
+        let mut file = AVSFile::open(&path).unwrap();
+        assert_eq!(file.veclen(), 3);
+        let vectors = file.read_vectors_f32().unwrap();
+        assert_eq!(vectors.len(), 4);
+        assert_eq!(vectors[1], vec![1.5, 2.0, 2.5]);
+    }
+
+    #[test]
+    fn decode_fixed_bytes() {
+        use DataType::*;
+        let cases: Vec<(DataType, Vec<u8>, f64)> = vec![
+            (Byte, vec![0xff], 255.0),
+            (Char, vec![0xff], -1.0),
+            (ShortLE, vec![0xfe, 0xff], -2.0),
+            (ShortBE, vec![0xff, 0xfe], -2.0),
+            (XDRShort, vec![0x12, 0x34], 4660.0),
+            (UShortLE, vec![0xfe, 0xff], 65534.0),
+            (UShortBE, vec![0xff, 0xfe], 65534.0),
+            (IntLE, vec![0x78, 0x56, 0x34, 0x12], 305419896.0),
+            (IntBE, vec![0xff, 0xff, 0xff, 0xfe], -2.0),
+            (XDRInt, vec![0x12, 0x34, 0x56, 0x78], 305419896.0),
+            (UIntLE, vec![0xfe, 0xff, 0xff, 0xff], 4294967294.0),
+            (UIntBE, vec![0xff, 0xff, 0xff, 0xfe], 4294967294.0),
+            (FloatLE, vec![0, 0, 0xc0, 0x3f], 1.5),
+            (FloatBE, vec![0x3f, 0xc0, 0, 0], 1.5),
+            (XDRFloat, vec![0xbf, 0xc0, 0, 0], -1.5),
+            (DoubleLE, vec![0, 0, 0, 0, 0, 0, 0x04, 0xc0], -2.5),
+            (DoubleBE, vec![0xc0, 0x04, 0, 0, 0, 0, 0, 0], -2.5),
+            (XDRDouble, vec![0x40, 0x04, 0, 0, 0, 0, 0, 0], 2.5),
+            (Short, (-300i16).to_ne_bytes().to_vec(), -300.0),
+            (UShort, 60000u16.to_ne_bytes().to_vec(), 60000.0),
+            (Int, (-70000i32).to_ne_bytes().to_vec(), -70000.0),
+            (UInt, 3000000000u32.to_ne_bytes().to_vec(), 3000000000.0),
+            (Float, 0.25f32.to_ne_bytes().to_vec(), 0.25),
+            (Double, 1e300f64.to_ne_bytes().to_vec(), 1e300),
+        ];
+        for (data_type, bytes, expected) in cases {
+            assert_eq!(bytes.len(), data_type.num_bytes(), "{:?}", data_type);
+            assert_eq!(data_type.convert_to_f64(&bytes), expected,
+                       "{:?}", data_type);
+        }
+    }
+
+    #[test]
+    fn integer_aliases() {
+        assert_eq!(DataType::from_str("integer").unwrap(), DataType::Int);
+        assert_eq!(DataType::from_str("xdr_integer").unwrap(), DataType::XDRInt);
+        let header: FldHeader = "ndim=1\ndim1=1\ndata=xdr_integer\nfield=uniform\n"
+            .parse().unwrap();
+        assert_eq!(header.data, DataType::XDRInt);
+    }
+
+    #[test]
+    fn read_as_rejects_lossy_types() {
+        let dir = temp_dir("lossy");
+        let path = dir.join("d.fld");
+        AVSFile::write(&mut File::create(&path).unwrap(), &[2], &[1.0f64, 2.0])
+            .unwrap();
+        let mut file = AVSFile::open(&path).unwrap();
+        match file.read_as::<f32>() {
+            Err(Error::TypeMismatch { header, element }) => {
+                assert_eq!(header, f64::data_type(ByteOrder::host()));
+                assert_eq!(element, DataType::Float);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(file.read_as::<f64>().unwrap(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn read_converted_reports_out_of_range_index() {
+        let dir = temp_dir("range");
+        let path = dir.join("d.fld");
+        let data = [1.0f64, -2.9, 3e9, 300.0];
+        AVSFile::write(&mut File::create(&path).unwrap(), &[4], &data).unwrap();
+        let mut file = AVSFile::open(&path).unwrap();
+        match file.read_to_i32() {
+            Err(Error::OutOfRange { index: 2, value }) => assert_eq!(value, 3e9),
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut file = AVSFile::open(&path).unwrap();
+        match file.read_to_u8() {
+            Err(Error::OutOfRange { index: 1, value }) => assert_eq!(value, -2.9),
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut file = AVSFile::open(&path).unwrap();
+        assert_eq!(file.read_to_f64().unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn external_ascii() {
+        let dir = temp_dir("ascii");
+        let path = dir.join("a.fld");
+        let data: Vec<i32> = vec![-5, 0, 7, 100000, 3, -2];
+        FldWriter::new(&[3]).veclen(2).external("data.txt", FileType::Ascii)
+            .create(&path, &data).unwrap();
+        assert!(dir.join("data.txt").exists());
+        let mut file = AVSFile::open(&path).unwrap();
+        assert_eq!(file.data_path(), Some(dir.join("data.txt").as_path()));
+        assert_eq!(file.read_as::<i32>().unwrap(), data);
+    }
+
+    #[test]
+    fn external_skip_offset_stride() {
+        let dir = temp_dir("stride");
+        // 3 header bytes, then the two components interleaved
+        std::fs::write(dir.join("d.raw"), [7, 7, 7, 1, 10, 2, 20, 3, 30])
+            .unwrap();
+        std::fs::write(dir.join("d.txt"), "title\n1 10 2\n20 3 30\n").unwrap();
+        let header = "ndim=1\ndim1=3\nveclen=2\ndata=byte\nfield=uniform\n\
+                      variable 1 file=d.raw filetype=binary skip=3 stride=2\n\
+                      variable 2 file=d.txt filetype=ascii skip=1 offset=1 stride=2\n";
+        std::fs::write(dir.join("s.fld"), header).unwrap();
+        let mut file = AVSFile::open(&dir.join("s.fld")).unwrap();
+        assert_eq!(file.read_to_u8().unwrap(), vec![1, 10, 2, 20, 3, 30]);
+    }
+
+    #[test]
+    fn split_files() {
+        for &filetype in &[FileType::Binary, FileType::Ascii] {
+            let dir = temp_dir("split");
+            let path = dir.join("s.fld");
+            let data: Vec<u16> = (0..12).collect();
+            FldWriter::new(&[2, 2]).veclen(3)
+                .split(&["a.dat", "b.dat", "c.dat"], filetype)
+                .create(&path, &data).unwrap();
+            let mut file = AVSFile::open(&path).unwrap();
+            assert_eq!(file.data_paths().len(), 3);
+            assert_eq!(file.read_as::<u16>().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rectilinear_field() {
+        let dir = temp_dir("rectilinear");
+        let path = dir.join("r.fld");
+        let coords = vec![vec![0.0, 1.0], vec![0.0, 0.5, 2.0]];
+        FldWriter::new(&[2, 3]).rectilinear(&coords, &["x.crd", "y.crd"])
+            .create(&path, &[0u8; 6]).unwrap();
+        let mut file = AVSFile::open(&path).unwrap();
+        assert_eq!(file.field_type, FieldType::Rectilinear);
+        assert_eq!(file.coords(), &coords[..]);
+        assert_eq!(file.read_to_u8().unwrap(), vec![0; 6]);
+    }
+
+
+    #[test]
+    fn write_with_header_rejects_coord_files() {
+        let mut header = FldHeader::new(&[2], DataType::Byte);
+        header.field = FieldType::Rectilinear;
+        header.coords.push(VariableFile::new(1, "x.crd"));
+        match AVSFile::write_with_header(&mut Vec::new(), &header, &[1u8, 2]) {
+            Err(Error::Invalid(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn create_rejects_wrong_coord_lengths() {
+        let dir = temp_dir("coord-lengths");
+        let path = dir.join("r.fld");
+        let mut header = FldHeader::new(&[2], DataType::Byte);
+        header.field = FieldType::Rectilinear;
+        header.coords.push(VariableFile::new(1, "x.crd"));
+        for coords in &[vec![], vec![vec![0.0]], vec![vec![0.0, 1.0, 2.0]]] {
+            match AVSFile::create_with_coords(&path, &header, &[1u8, 2],
+                                              coords) {
+                Err(Error::File { source, .. }) => match *source {
+                    Error::Invalid(_) => {},
+                    other => panic!("unexpected {:?}", other),
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(!path.exists());
+        assert!(!dir.join("x.crd").exists());
+
+        header.field = FieldType::Irregular;
+        header.nspace = 1;
+        match AVSFile::create_with_coords(&path, &header, &[1u8, 2],
+                                          &[vec![0.0]]) {
+            Err(Error::File { source, .. }) => match *source {
+                Error::Invalid(_) => {},
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        AVSFile::create_with_coords(&path, &header, &[1u8, 2],
+                                    &[vec![0.0, 3.0]]).unwrap();
+        assert_eq!(AVSFile::open(&path).unwrap().coords(),
+                   &[vec![0.0, 3.0]][..]);
+    }
+
+    #[test]
+    fn irregular_field() {
+        let dir = temp_dir("irregular");
+        let path = dir.join("i.fld");
+        let coords = vec![vec![0.0, 1.0, 0.0, 1.0], vec![0.0, 0.0, 1.0, 1.5],
+                          vec![2.0; 4]];
+        FldWriter::new(&[2, 2])
+            .irregular(&coords, &["x.crd", "y.crd", "z.crd"])
+            .create(&path, &[1.0f64, 2.0, 3.0, 4.0]).unwrap();
+        let mut file = AVSFile::open(&path).unwrap();
+        assert_eq!(file.header.nspace, 3);
+        assert_eq!(file.coords(), &coords[..]);
+        assert_eq!(file.read_to_f64().unwrap(), vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+
+    #[test]
+    fn write_checks_dims_against_data() {
+        let mut out = Vec::new();
+        match AVSFile::write(&mut out, &[2, 3], &[0u8; 5]) {
+            Err(Error::SizeMismatch { expected: 6, found: 5 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match AVSFile::write(&mut out, &[2, 0], &[0u8; 0]) {
+            Err(Error::ZeroDim { axis: 2 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match AVSFile::write(&mut out, &[usize::MAX, 2], &[0u8; 1]) {
+            Err(Error::Overflow) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match AVSFile::write::<_, u8>(&mut out, &[], &[]) {
+            Err(Error::Malformed { .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(out.is_empty());
+    }
+
+
+    #[test]
+    fn open_errors_name_the_file_and_cause() {
+        use std::error::Error as StdError;
+        let dir = temp_dir("bad-header");
+        let path = dir.join("bad.fld");
+        std::fs::write(&path, "# c\nndim=1\ndim1=x\ndata=byte\nfield=uniform\n\
+                               \x0c\x0c").unwrap();
+        let err = match AVSFile::open(&path) {
+            Err(err) => err,
+            Ok(_) => panic!("opened a bad header"),
+        };
+        assert!(err.to_string().starts_with(&path.display().to_string()));
+        let parse = err.source().unwrap();
+        assert_eq!(parse.to_string(),
+                   "line 3: cannot parse \"x\" as the value of dim1");
+        let cause = parse.source().unwrap();
+        assert!(cause.is::<std::num::ParseIntError>());
+        assert!(cause.source().is_none());
+    }
+
+    #[test]
+    fn truncated_external_files() {
+        let dir = temp_dir("truncated");
+        std::fs::write(dir.join("d.txt"), "1 2\n3\n").unwrap();
+        std::fs::write(dir.join("d.raw"), [0u8; 7]).unwrap();
+        for &(file, filetype, found) in &[("d.txt", "ascii", 3),
+                                          ("d.raw", "binary", 1)] {
+            let header = format!("ndim=1\ndim1=4\ndata=float\nfield=uniform\n\
+                                  variable 1 file={} filetype={}\n",
+                                 file, filetype);
+            std::fs::write(dir.join("t.fld"), header).unwrap();
+            let mut file = AVSFile::open(&dir.join("t.fld")).unwrap();
+            match file.read_to_f32() {
+                Err(Error::File { source, .. }) => match *source {
+                    Error::Truncated { expected: 4, found: f } =>
+                        assert_eq!(f, found),
+                    other => panic!("unexpected {:?}", other),
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::io::Write;
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+
+use super::{AVSFile, ByteOrder, Error, FieldType, FileType, FldElement,
+            FldHeader, VariableFile};
+
+/// Collects the options for writing a field, checks them against each other
+/// and against the data, and writes the result.
+#[derive(Debug, Clone)]
+pub struct FldWriter {
+    dims: Vec<usize>,
+    veclen: usize,
+    order: ByteOrder,
+    min_ext: Vec<f64>,
+    max_ext: Vec<f64>,
+    labels: Vec<String>,
+    units: Vec<String>,
+    comments: Vec<String>,
+    entries: Vec<(String, String)>,
+    filetype: FileType,
+    files: Vec<String>,
+    field: FieldType,
+    coord_files: Vec<String>,
+    coords: Vec<Vec<f32>>,
+}
+
+impl FldWriter {
+    /// A writer for a scalar uniform field of the given dimensions, stored
+    /// inline in this machine's byte order.
+    pub fn new(dims: &[usize]) -> FldWriter {
+        FldWriter {
+            dims: dims.to_vec(),
+            veclen: 1,
+            order: ByteOrder::host(),
+            min_ext: Vec::new(),
+            max_ext: Vec::new(),
+            labels: Vec::new(),
+            units: Vec::new(),
+            comments: Vec::new(),
+            entries: Vec::new(),
+            filetype: FileType::Binary,
+            files: Vec::new(),
+            field: FieldType::Uniform,
+            coord_files: Vec::new(),
+            coords: Vec::new(),
+        }
+    }
+
+    /// Sets the number of interleaved components per grid point.
+    pub fn veclen(mut self, veclen: usize) -> FldWriter {
+        self.veclen = veclen;
+        self
+    }
+
+    pub fn byte_order(mut self, order: ByteOrder) -> FldWriter {
+        self.order = order;
+        self
+    }
+
+    /// Sets `min_ext` and `max_ext`, one value per axis.
+    pub fn extents(mut self, min: &[f64], max: &[f64]) -> FldWriter {
+        self.min_ext = min.to_vec();
+        self.max_ext = max.to_vec();
+        self
+    }
+
+    /// Sets the axis labels, one per axis.
+    pub fn labels(mut self, labels: &[&str]) -> FldWriter {
+        self.labels = labels.iter().map(|l| l.to_string()).collect();
+        self
+    }
+
+    /// Sets the axis units, one per axis.
+    pub fn units(mut self, units: &[&str]) -> FldWriter {
+        self.units = units.iter().map(|u| u.to_string()).collect();
+        self
+    }
+
+    /// Adds a comment line after the avsfldrs banner.
+    pub fn comment(mut self, comment: &str) -> FldWriter {
+        self.comments.push(comment.to_string());
+        self
+    }
+
+    /// Adds an extra `key=value` entry; see `FldHeader::add_entry`.
+    pub fn entry(mut self, key: &str, value: &str) -> FldWriter {
+        self.entries.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Stores the payload in the external file `file` rather than inline.
+    pub fn external(mut self, file: &str, filetype: FileType) -> FldWriter {
+        self.files = vec![file.to_string()];
+        self.filetype = filetype;
+        self
+    }
+
+    /// Stores each vector component in its own external file; `files` needs
+    /// one name per component.
+    pub fn split(mut self, files: &[&str], filetype: FileType) -> FldWriter {
+        self.files = files.iter().map(|f| f.to_string()).collect();
+        self.filetype = filetype;
+        self
+    }
+
+    /// Makes the field rectilinear; `coords[N-1]` holds the `dims[N-1]`
+    /// positions along axis N and is written to `files[N-1]`.
+    pub fn rectilinear(mut self, coords: &[Vec<f32>], files: &[&str])
+            -> FldWriter {
+        self.field = FieldType::Rectilinear;
+        self.coords = coords.to_vec();
+        self.coord_files = files.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    /// Makes the field irregular; `coords[N-1]` holds coordinate N of every
+    /// grid point and is written to `files[N-1]`.  `nspace` is the number of
+    /// coordinate arrays.
+    pub fn irregular(mut self, coords: &[Vec<f32>], files: &[&str])
+            -> FldWriter {
+        self.field = FieldType::Irregular;
+        self.coords = coords.to_vec();
+        self.coord_files = files.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    /// Builds the header describing `T` data, checking that the options are
+    /// consistent.
+    pub fn header<T: FldElement>(&self) -> Result<FldHeader, Error> {
+        let ndim = self.dims.len();
+        let mut header = FldHeader::new(&self.dims, T::data_type(self.order));
+
+        if self.veclen == 0 {
+            return Err(invalid("veclen must be positive"));
+        }
+        header.veclen = self.veclen;
+        header.checked_num_values()?;
+
+        if self.min_ext.len() != self.max_ext.len()
+                || (!self.min_ext.is_empty() && self.min_ext.len() != ndim) {
+            return Err(invalid("extents need one value per axis"));
+        }
+        header.min_ext = self.min_ext.clone();
+        header.max_ext = self.max_ext.clone();
+
+        if (!self.labels.is_empty() && self.labels.len() != ndim)
+                || (!self.units.is_empty() && self.units.len() != ndim) {
+            return Err(invalid("labels and units need one word per axis"));
+        }
+        header.labels = self.labels.clone();
+        header.units = self.units.clone();
+
+        for comment in &self.comments {
+            header.add_comment(comment);
+        }
+        for (key, value) in &self.entries {
+            header.add_entry(key, value)?;
+        }
+
+        if self.files.len() > 1 && self.files.len() != self.veclen {
+            return Err(invalid("split needs one file per component"));
+        }
+        header.variables = self.files.iter().enumerate()
+            .map(|(idx, file)| VariableFile {
+                filetype: self.filetype,
+                ..VariableFile::new(idx + 1, file)
+            })
+            .collect();
+
+        header.field = self.field;
+        if self.coords.len() != self.coord_files.len() {
+            return Err(invalid("coordinates need one file per array"));
+        }
+        match self.field {
+            FieldType::Uniform => {},
+            FieldType::Rectilinear => {
+                if self.coords.len() != ndim {
+                    return Err(invalid(
+                        "rectilinear needs one coordinate array per axis"));
+                }
+            },
+            FieldType::Irregular => {
+                if self.coords.is_empty() {
+                    return Err(invalid(
+                        "irregular needs at least one coordinate array"));
+                }
+                header.nspace = self.coords.len();
+            },
+        }
+        header.coords = self.coord_files.iter().enumerate()
+            .map(|(idx, file)| VariableFile::new(idx + 1, file))
+            .collect();
+
+        header.check()?;
+        header.check_coords(&self.coords)?;
+        Ok(header)
+    }
+
+    /// Writes the header and inline payload to `writer`.  Fields with
+    /// external data or coordinate files must be written with `create`.
+    pub fn write<W: Write, T: FldElement>(&self, writer: &mut W, data: &[T])
+            -> Result<(), Error> {
+        if !self.files.is_empty() || !self.coord_files.is_empty() {
+            return Err(Error::NotImplemented(
+                "FldWriter::write with external files; use create"));
+        }
+        let header = self.header::<T>()?;
+        AVSFile::write_with_header(writer, &header, data)
+    }
+
+    /// Writes the field to a new file at `path`, along with any external
+    /// data and coordinate files, which are placed relative to it.
+    pub fn create<P: AsRef<Path>, T: FldElement>(&self, path: &P, data: &[T])
+            -> Result<(), Error> {
+        let header = self.header::<T>()?;
+        AVSFile::create_with_coords(path, &header, data, &self.coords)
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::Invalid(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_err(writer: FldWriter) -> String {
+        match writer.header::<f32>() {
+            Err(Error::Invalid(reason)) => reason,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn header_checks_options() {
+        let header = FldWriter::new(&[2, 3]).veclen(2)
+            .extents(&[0.0, 0.0], &[1.0, 1.0])
+            .labels(&["x", "y"]).units(&["mm", "mm"])
+            .split(&["a.raw", "b.raw"], FileType::Ascii)
+            .header::<f32>().unwrap();
+        assert_eq!(header.variables.len(), 2);
+
+        header_err(FldWriter::new(&[2]).veclen(0));
+        header_err(FldWriter::new(&[2, 3]).extents(&[0.0], &[1.0]));
+        header_err(FldWriter::new(&[2, 3]).extents(&[0.0, 0.0], &[1.0]));
+        header_err(FldWriter::new(&[2, 3]).labels(&["x"]));
+        header_err(FldWriter::new(&[2, 3]).units(&["mm", "mm", "mm"]));
+        header_err(FldWriter::new(&[2]).veclen(3)
+                   .split(&["a.raw", "b.raw"], FileType::Binary));
+    }
+
+    #[test]
+    fn header_checks_coordinates() {
+        let x = vec![0.0, 1.0];
+        let y = vec![0.0, 1.0, 2.0];
+        assert!(FldWriter::new(&[2, 3]).rectilinear(&[x.clone(), y.clone()],
+                                                    &["x", "y"])
+                .header::<u8>().is_ok());
+        header_err(FldWriter::new(&[2, 3])
+                   .rectilinear(&[vec![0.0; 2]], &["x"]));
+        header_err(FldWriter::new(&[2, 3])
+                   .rectilinear(&[x.clone(), y.clone()], &["x"]));
+        header_err(FldWriter::new(&[2, 3])
+                   .rectilinear(&[y.clone(), x.clone()], &["x", "y"]));
+        header_err(FldWriter::new(&[2, 3]).irregular(&[], &[]));
+        header_err(FldWriter::new(&[2, 3]).irregular(&[x], &["x"]));
+        assert_eq!(FldWriter::new(&[2, 3]).irregular(&[vec![0.0; 6]], &["x"])
+                   .header::<u8>().unwrap().nspace, 1);
+    }
+}